@@ -0,0 +1,35 @@
+// get on sealed KvStore segments: pread into a buffer vs slicing a memory map
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+
+fn get_sealed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_sealed");
+    for mmap in [false, true] {
+        let temp_dir = TempDir::new().unwrap();
+        // small files so every record but the last few is in a sealed file
+        let options = KvStoreOptions::new()
+            .max_file_size(64 * 1024)
+            .mmap_sealed_files(mmap);
+        let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), "x".repeat(256)).unwrap();
+        }
+
+        let mut rng = SmallRng::from_seed([0; 16]);
+        let name = if mmap { "mmap" } else { "pread" };
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let key = format!("key{}", rng.gen_range(0, KEYS));
+                store.get(key).unwrap().unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get_sealed);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+// concurrent set with an fsync per write: group commit lets the threads share the syncs
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SyncPolicy};
+use std::thread;
+use tempfile::TempDir;
+
+const SETS_PER_THREAD: usize = 50;
+
+fn concurrent_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set");
+    group.sample_size(10);
+    for threads in [1, 4, 16] {
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+        let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for i in 0..SETS_PER_THREAD {
+                                store.set(format!("key{}_{}", thread_id, i), "value".to_owned()).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_set);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use clap::{arg, command, ArgMatches, Command};
+use kvs::dump::{self, DumpFormat};
+use kvs::{EngineType, KvStore, KvStoreOptions, KvsEngine, Result, SledKvStore, SyncPolicy};
+
+//offline tool for the store a kvs-server serves from --dir, the server must not be running
+fn main() -> Result<()> {
+    let store_args = [
+        arg!(<FILE>).help("The dump file, - for stdout/stdin"),
+        arg!(-d --dir <path> "Dir the kvs-server runs in, holding kvs/ or sled/").default_value("."),
+        arg!(-e --engine <engine_name> "sled or kvs").value_parser(["kvs", "sled"]),
+        arg!(-f --format <format> "jsonl or binary").default_value("jsonl")
+            .value_parser(|s: &str| s.parse::<DumpFormat>().map_err(|e| e.to_string())),
+        arg!(-r --resume <KEY> "Only the pairs after this checkpoint key, as printed by an interrupted run"),
+    ];
+    let matches = command!()
+        .propagate_version(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("export")
+                .about("write every key/value pair of the store to a dump: export <file>")
+                .args(store_args.clone())
+        )
+        .subcommand(
+            Command::new("import")
+                .about("load the pairs of a dump into the store, creating it if needed: import <file>")
+                .args(store_args)
+        )
+        .get_matches();
+
+    if let Err(err) = run(matches) {
+        //the message, e.g. of StoreLocked, tells what to do
+        eprintln!("{}", err);
+        process::exit(-1);
+    }
+    Ok(())
+}
+
+fn run(matches: ArgMatches) -> Result<()> {
+    let (name, sub_matches) = matches.subcommand().expect("subcommand is required");
+    let dir = PathBuf::from(sub_matches.get_one::<String>("dir").unwrap());
+    let engine_type = EngineType::judge(&dir, sub_matches.get_one::<String>("engine").map(String::as_str))?;
+    let path = dir.join(engine_type.to_string());
+    //export must not leave an empty store behind
+    if name == "export" && !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no store in {:?}", dir)).into());
+    }
+    //import flushes the store at every checkpoint itself
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Never);
+    match engine_type {
+        EngineType::KvStore => run_with(KvStore::open_with(path, options)?, name, sub_matches),
+        EngineType::SledKvStore => run_with(SledKvStore::open_with(path, options)?, name, sub_matches),
+    }
+}
+
+fn run_with<E: KvsEngine>(engine: E, name: &str, sub_matches: &ArgMatches) -> Result<()> {
+    let file = sub_matches.get_one::<String>("FILE").unwrap();
+    let format = *sub_matches.get_one::<DumpFormat>("format").unwrap();
+    let resume_after = sub_matches.get_one::<String>("resume").map(|key| dump::parse_key(key));
+    //the progress goes to stderr, stdout may be the dump itself
+    let progress = |verb: &'static str| move |count: u64, last_key: &[u8]| {
+        eprintln!("{} {} pairs, checkpoint key: {}", verb, count, dump::display_key(last_key));
+    };
+    match name {
+        "export" => {
+            let writer: Box<dyn Write> = match file.as_str() {
+                "-" => Box::new(io::stdout().lock()),
+                file => Box::new(File::create(Path::new(file))?),
+            };
+            dump::export(&engine, writer, format, resume_after.as_deref(), progress("exported"))?;
+        }
+        "import" => {
+            let reader: Box<dyn Read> = match file.as_str() {
+                "-" => Box::new(io::stdin().lock()),
+                file => Box::new(File::open(Path::new(file))?),
+            };
+            dump::import(&engine, reader, format, resume_after.as_deref(), progress("imported"))?;
+        }
+        _ => unreachable!("unknown subcommand {}", name),
+    }
+    Ok(())
+}
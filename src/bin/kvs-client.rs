@@ -1,11 +1,7 @@
-use std::net::TcpStream;
-use std::{env, process};
+use std::process;
+use std::time::Duration;
 use clap::{arg, command, Command, ArgMatches};
-use kvs::{Result};
-use serde::Deserialize;
-//use serde::Deserializer;
-use std::io::{BufReader,BufWriter,Write};
-use kvs::{Request,Response};
+use kvs::{KvsClient, Result};
 
 //build the Command instance
 fn main() -> Result<()> {
@@ -24,6 +20,7 @@ fn main() -> Result<()> {
                 .about("set a key/vaule pair: set <key> <vaule>")
                 .arg(arg!(<KEY>).help("A String key").required(true))
                 .arg(arg!(<VALUE>).help("A String vaule").required(true))
+                .arg(arg!(--ttl <seconds> "Remove the key after this many seconds").value_parser(clap::value_parser!(u64)))
                 .arg(arg!(-a --addr <ipport> "example: 127.0.0.1:4000").required(true).default_value("127.0.0.1:4000"))
         )
         .subcommand(
@@ -32,6 +29,23 @@ fn main() -> Result<()> {
                 .arg(arg!(<KEY>).help("A String key").required(true))
                 .arg(arg!(-a --addr <ipport> "example: 127.0.0.1:4000").required(true).default_value("127.0.0.1:4000"))
         )
+        .subcommand(
+            Command::new("incr")
+                .about("add to the integer at a key and print the result: incr <key> [delta]")
+                .arg(arg!(<KEY>).help("A String key").required(true))
+                .arg(arg!([DELTA]).help("Amount to add, may be negative").value_parser(clap::value_parser!(i64))
+                    .allow_negative_numbers(true).default_value("1"))
+                .arg(arg!(-a --addr <ipport> "example: 127.0.0.1:4000").required(true).default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("list key/vaule pairs in key order: scan [START] [END] or scan --prefix <prefix>")
+                .arg(arg!([START]).help("First key of the range, included"))
+                .arg(arg!([END]).help("Last key of the range, excluded"))
+                .arg(arg!(-p --prefix <prefix> "Only keys starting with prefix").conflicts_with_all(["START", "END", "limit"]))
+                .arg(arg!(-l --limit <n> "Max number of pairs").value_parser(clap::value_parser!(usize)).default_value("100"))
+                .arg(arg!(-a --addr <ipport> "example: 127.0.0.1:4000").required(true).default_value("127.0.0.1:4000"))
+        )
         .get_matches(); //get the command struct
 
         if let Err(err) = send_request(matches) {
@@ -47,63 +61,53 @@ fn main() -> Result<()> {
     //再对command本身进行模式匹配，根据不同的命令进行后续操作
     //比如get command
     fn send_request(matches:ArgMatches) -> Result<()> {
-        match matches.subcommand() {
-            Some(("get", _matches)) => {
+        let (name, _matches) = match matches.subcommand() {
+            Some(subcommand) => subcommand,
+            None => process::exit(-1),
+        };
+        let addr = _matches.get_one::<String>("addr").unwrap();
+        //拿到了server ip, 建立一个连接, every request of this run goes through it
+        let mut client = KvsClient::connect(addr.as_str())?;
+        match name {
+            "get" => {
                 let key = _matches.get_one::<String>("KEY").unwrap();
-                let addr = _matches.get_one::<String>("addr").unwrap();
-                //拿到了server ip和要查询的key
-                //需要建立连接
-                let mut client = Client::new(&addr)?;
-                match client.request(&Request::GET(key.to_owned()))? {
+                match client.get(key.to_owned())? {
                     Some(val) => println!("{}", val),
                     None =>println!("Key not found"),
                 };
             },
-            Some(("set", _matches)) => {
+            "set" => {
                 let key = _matches.get_one::<String>("KEY").unwrap();
                 let value = _matches.get_one::<String>("VALUE").unwrap();
-                let addr = _matches.get_one::<String>("addr").unwrap();
-                let mut client = Client::new(&addr)?;
-                client.request(&Request::SET(key.to_owned(), value.to_owned()))?;  
+                match _matches.get_one::<u64>("ttl") {
+                    Some(ttl) => client.set_with_ttl(key.to_owned(), value.to_owned(), Duration::from_secs(*ttl))?,
+                    None => client.set(key.to_owned(), value.to_owned())?,
+                }
             },
-            Some(("rm", _matches)) => {
+            "rm" => {
                 let key = _matches.get_one::<String>("KEY").unwrap();
-                let addr = _matches.get_one::<String>("addr").unwrap();
-                let mut client = Client::new(&addr)?;
-                client.request(&Request::RM(key.to_owned()))?;
+                client.remove(key.to_owned())?;
+            },
+            "incr" => {
+                let key = _matches.get_one::<String>("KEY").unwrap();
+                let delta = *_matches.get_one::<i64>("DELTA").unwrap();
+                println!("{}", client.incr(key.to_owned(), delta)?);
+            },
+            "scan" => {
+                let pairs = match _matches.get_one::<String>("prefix") {
+                    Some(prefix) => client.scan_prefix(prefix.to_owned())?,
+                    None => {
+                        let start = _matches.get_one::<String>("START").cloned().unwrap_or_default();
+                        let end = _matches.get_one::<String>("END").cloned();
+                        let limit = *_matches.get_one::<usize>("limit").unwrap();
+                        client.scan(start, end, limit)?
+                    }
+                };
+                for (key, value) in pairs {
+                    println!("{}\t{}", key, value);
+                }
             },
             _ => process::exit(-1),
         }
         Ok(())    
     }
-
-struct Client {
-    //for response
-    reader: serde_json::Deserializer<serde_json::de::IoRead<BufReader<TcpStream>>>,
-    //for request
-    writer: BufWriter<TcpStream>,
-}
-
-impl Client {
-    fn new(addr: &str) -> Result<Client> {
-        let stream = TcpStream::connect(addr)?; 
-        Ok(Client {
-            reader: serde_json::Deserializer::from_reader(BufReader::new(stream.try_clone()?)), //try clone的错是啥？
-            writer: BufWriter::new(stream), //client往里写request
-        })
-    }
-
-    fn request(&mut self, request: &Request) -> Result<Option<String>> {
-        // 把request序列化为JSON, 然后放进Client::writer (or IO stream)
-        serde_json::to_writer(&mut self.writer, request)?;
-        //flush this output stream to server
-        self.writer.flush()?; //flush cannot be detected.
-
-        //client处理server发过来的respone
-        //发response的逻辑在server.rs
-        match Response::deserialize(&mut self.reader)? {
-            Response::Ok(val) => Ok(val),
-            Response::Err(err) => Err(kvs::KVStoreError::ServerError(err)),
-        } 
-    }
-}
@@ -1,10 +1,13 @@
-use kvs::{KVStoreError, EngineType, KvsEngine,KvServer,Result, KvStore,SledKvStore};
-use kvs::thread_pool::{ThreadPool,SharedQueueThreadPool,NaiveThreadPool,RayonThreadPool};
-use clap::{arg,command, ArgMatches};
+use kvs::{EngineType, KvsClient, KvsEngine,KvServer,Result, KvStore,KvStoreOptions,SledKvStore,SyncPolicy};
+use kvs::thread_pool::{ThreadPool,SharedQueueThreadPool};
+use clap::{arg,command, ArgMatches, Command};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::{env};
 use log::{info, LevelFilter};
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
 
 fn main() -> Result<()> {
     //logger 
@@ -16,12 +19,64 @@ fn main() -> Result<()> {
         arg!(-a --addr <ipport> "example: 127.0.0.1:4000")
         .required(false)
         .default_value("127.0.0.1:4000")
+        .global(true)
     )
     //存在不指定engine_type的情况
     .arg(
         arg!(-e --engine <engine_name> "sled or kvs")
         .required(false)
-        .value_parser(["kvs", "sled"]),
+        .value_parser(["kvs", "sled"])
+        .global(true),
+    )
+    .arg(
+        arg!(-s --sync <policy> "never, every-write or interval:<ms>")
+        .required(false)
+        .value_parser(|s: &str| s.parse::<SyncPolicy>().map_err(|e| e.to_string())),
+    )
+    //KvStore tuning, ignored by sled
+    .arg(
+        arg!(--"compaction-threshold" <bytes> "compact once more than this many bytes are stale")
+        .required(false)
+        .value_parser(clap::value_parser!(u64)),
+    )
+    .arg(
+        arg!(--"compaction-ratio" <ratio> "and the stale bytes are at least this fraction of the logs")
+        .required(false)
+        .value_parser(clap::value_parser!(f64)),
+    )
+    .arg(
+        arg!(--"max-file-size" <bytes> "roll to a new log file at this size")
+        .required(false)
+        .value_parser(clap::value_parser!(u64).range(1..)),
+    )
+    .arg(
+        arg!(--"reader-cache-size" <files> "max open log files")
+        .required(false)
+        .value_parser(clap::value_parser!(usize)),
+    )
+    .arg(
+        arg!(--mmap "read sealed log files through a memory map")
+    )
+    .arg(
+        arg!(--"value-cache-size" <bytes> "cache this many bytes of hot values, 0 for no cache")
+        .required(false)
+        .value_parser(clap::value_parser!(usize)),
+    )
+    //without it clients can not back up the store
+    .arg(
+        arg!(--"backup-dir" <dir> "where clients may write backups, each into a directory of its own")
+        .required(false),
+    )
+    //without a subcommand the server serves the store of the current dir
+    .subcommand(
+        Command::new("backup")
+            .about("ask the server at --addr to back up its store: backup <name>")
+            .arg(arg!(<NAME>).help("An empty or missing directory right in the --backup-dir of the server"))
+    )
+    .subcommand(
+        Command::new("restore")
+            .about("restore a backup as the store of the current dir, before serving it: restore <path>")
+            .arg(arg!(<PATH>).help("A directory written by backup"))
     )
     .get_matches();
     if let Err(err) = init(matches) {
@@ -39,64 +94,130 @@ fn init(matches: ArgMatches) -> Result<()> {
     let engine_type_userspecified = matches.get_one::<String>
     ("engine");
 
+    match matches.subcommand() {
+        Some(("backup", sub_matches)) => return backup(addr, sub_matches.get_one::<String>("NAME").unwrap()),
+        Some(("restore", sub_matches)) => {
+            return restore(engine_type_userspecified.cloned(), sub_matches.get_one::<String>("PATH").unwrap())
+        }
+        _ => {}
+    }
+
     //logger
     info!("Version: {}",env!("CARGO_PKG_VERSION"));
     info!("Addr: [{}]", addr);
-    info!("EngineTypeSpecifiedByUser: [{}]", engine_type_userspecified.unwrap());
+    info!("EngineTypeSpecifiedByUser: [{:?}]", engine_type_userspecified);
 
     let engine_type = judge_engine(engine_type_userspecified.cloned())?;
     info!("engine_type: [{}]", engine_type);
+
+    let options = engine_options(&matches, &engine_type);
+    info!("options: {:?}", options);
+    //a relative dir is taken from the current dir
+    let backup_dir = match matches.get_one::<String>("backup-dir") {
+        Some(dir) => Some(env::current_dir()?.join(dir)),
+        None => None,
+    };
+    info!("backup dir: {:?}", backup_dir);
     
     match engine_type {
         EngineType::KvStore => {
-            run_server(KvStore::open(env::current_dir()?.join(EngineType::KvStore.to_string()))?, addr)
+            let path = env::current_dir()?.join(EngineType::KvStore.to_string());
+            run_server(KvStore::open_with(path, options)?, addr, backup_dir)
         },
         EngineType::SledKvStore => {
-            run_server(SledKvStore::open(env::current_dir()?.join(EngineType::SledKvStore.to_string()))?, addr)
+            let path = env::current_dir()?.join(EngineType::SledKvStore.to_string());
+            run_server(SledKvStore::open_with(path, options)?, addr, backup_dir)
         },
     }
 }
 
+//the server writes the backup into its --backup-dir
+fn backup(addr: &str, name: &str) -> Result<()> {
+    KvsClient::connect(addr)?.backup(name.to_owned())?;
+    info!("backup written to {:?} in the backup dir of the server", name);
+    Ok(())
+}
+
+//the engine of the backup is told by its files unless --engine is given
+//like serving, restoring must not change the engine of the current dir
+fn restore(engine_type_userspecified: Option<String>, path: &str) -> Result<()> {
+    let backup_path = Path::new(path);
+    let engine_name = engine_type_userspecified.unwrap_or_else(|| {
+        if backup_path.join("MANIFEST").exists() {
+            EngineType::KvStore.to_string()
+        } else {
+            EngineType::SledKvStore.to_string()
+        }
+    });
+    let engine_type = judge_engine(Some(engine_name))?;
+    let open_path = env::current_dir()?.join(engine_type.to_string());
+    match engine_type {
+        EngineType::KvStore => drop(KvStore::restore(backup_path, &open_path)?),
+        EngineType::SledKvStore => drop(SledKvStore::restore(backup_path, &open_path)?),
+    }
+    info!("backup {:?} restored into {:?}", backup_path, open_path);
+    Ok(())
+}
+
+//KvStoreOptions from the flags, defaults for the ones not given
+fn engine_options(matches: &ArgMatches, engine_type: &EngineType) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    //without --sync each engine keeps its own default: sled flushes every write
+    match (matches.get_one::<SyncPolicy>("sync"), engine_type) {
+        (Some(sync_policy), _) => options = options.sync_policy(*sync_policy),
+        (None, EngineType::SledKvStore) => options = options.sync_policy(SyncPolicy::EveryWrite),
+        (None, EngineType::KvStore) => {}
+    }
+    if let Some(bytes) = matches.get_one::<u64>("compaction-threshold") {
+        options = options.compaction_threshold(*bytes);
+    }
+    if let Some(ratio) = matches.get_one::<f64>("compaction-ratio") {
+        options = options.compaction_ratio(*ratio);
+    }
+    if let Some(bytes) = matches.get_one::<u64>("max-file-size") {
+        options = options.max_file_size(*bytes);
+    }
+    if let Some(files) = matches.get_one::<usize>("reader-cache-size") {
+        options = options.reader_cache_size(*files);
+    }
+    if matches.get_flag("mmap") {
+        options = options.mmap_sealed_files(true);
+    }
+    if let Some(bytes) = matches.get_one::<usize>("value-cache-size") {
+        options = options.value_cache_size(*bytes);
+    }
+    options
+}
+
 //根据当前engine是否在当前路径已经初始化来决定enginetype和返回错误
 //当前engine是否在当前路径已经初始化，不允许更改engineType, 使用open()初始化
 fn judge_engine(engine_type: Option<String>) -> Result<EngineType> {
-    let curr_dir = env::current_dir()?;
-    match engine_type {
-        None => {
-            //impl Display trait for enum EngineType in server.rs
-            if curr_dir.join(EngineType::SledKvStore.to_string()).exists() {
-                return Ok(EngineType::SledKvStore);
-            }
-            return Ok(EngineType::KvStore)
-        }
-        Some(eg) => {
-            if eg == EngineType::SledKvStore.to_string() {
-                if curr_dir.join(EngineType::KvStore.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
-                }
-                Ok(EngineType::SledKvStore)
-            } else {
-                if curr_dir.join(EngineType::SledKvStore.to_string()).exists() {
-                    return Err(KVStoreError::ChangeEngineError);
-                }
-                return Ok(EngineType::KvStore)
-            }
-        }
-    }
+    EngineType::judge(&env::current_dir()?, engine_type.as_deref())
 }
 
 //构造并运行KvsServer实例并监听处理stream在server()函数
 //engine: 是KvStore实例或者是SledKvStore实例
-fn run_server<E>(engine: E,addr: &String,) -> Result<()> 
+fn run_server<E>(engine: E,addr: &String, backup_dir: Option<PathBuf>) -> Result<()> 
 where E: KvsEngine
 {   
     info!("running server with engine_type");
+    let is_stop = Arc::new(AtomicBool::new(false));
+    //SIGINT/SIGTERM set is_stop so serve() drains and returns
+    //a second signal while still draining terminates the process immediately
+    for sig in TERM_SIGNALS {
+        flag::register_conditional_shutdown(*sig, 1, Arc::clone(&is_stop))?;
+        flag::register(*sig, Arc::clone(&is_stop))?;
+    }
     let mut server = KvServer::new(
         engine,
         SharedQueueThreadPool::new(num_cpus::get())?,
-        Arc::new(AtomicBool::new(false)),
+        is_stop,
     );
+    if let Some(backup_dir) = backup_dir {
+        server = server.backup_dir(backup_dir);
+    }
     server.serve(addr)?;
+    info!("server exited gracefully");
     Ok(())
 }
 
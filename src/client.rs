@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use crate::{KVStoreError, Request, Response, Result, Transaction, WriteBatch};
+use crate::protocol;
+
+// KvsClient talks to a KvServer over one persistent TcpStream
+// requests are answered in order, so a client is used by one thread at a time
+pub struct KvsClient {
+    //for response
+    reader: BufReader<TcpStream>,
+    //for request
+    writer: BufWriter<TcpStream>,
+    //set once a request failed halfway, the stream may hold the rest of its response
+    broken: bool,
+}
+
+impl KvsClient {
+    // connect without any timeout, every call blocks until the server answers
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        KvsClient::from_stream(stream)
+    }
+
+    // connect with `timeout` used for the connect itself and for every read and write after it
+    // a request running longer than `timeout` fails with an IoError of kind WouldBlock/TimedOut
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<KvsClient> {
+        let mut last_err = None;
+        //try every resolved address like TcpStream::connect does
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(stream) => {
+                    let client = KvsClient::from_stream(stream)?;
+                    client.set_read_timeout(Some(timeout))?;
+                    client.set_write_timeout(Some(timeout))?;
+                    return Ok(client);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address"))
+            .into())
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<KvsClient> {
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            broken: false,
+        })
+    }
+
+    // None disables the timeout
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.get_ref().set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    // None disables the timeout
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.writer.get_ref().set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::GET(key.to_vec()))? {
+            Response::Ok(val) => Ok(val),
+            _ => Err(KVStoreError::UnexpectedResponse),
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.request(&Request::SET(key.to_vec(), value.to_vec()))?;
+        Ok(())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.request(&Request::RM(key.to_vec()))?;
+        Ok(())
+    }
+
+    // pairs with start <= key < end (no upper bound if end is None) in byte order, at most limit of them
+    pub fn scan_bytes(&mut self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.request(&Request::SCAN(start.to_vec(), end.map(<[u8]>::to_vec), limit))? {
+            Response::Scan(pairs) => Ok(pairs),
+            _ => Err(KVStoreError::UnexpectedResponse),
+        }
+    }
+
+    pub fn scan_prefix_bytes(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.request(&Request::SCAN_PREFIX(prefix.to_vec()))? {
+            Response::Scan(pairs) => Ok(pairs),
+            _ => Err(KVStoreError::UnexpectedResponse),
+        }
+    }
+
+    // fails with Utf8Error if the value is not UTF-8, get_bytes reads it anyway
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    // the key is gone once ttl has passed, see KvsEngine::set_with_ttl_bytes
+    pub fn set_with_ttl_bytes(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let ttl_ms = ttl.as_millis().try_into().unwrap_or(u64::MAX);
+        self.request(&Request::SET_TTL(key.to_vec(), value.to_vec(), ttl_ms))?;
+        Ok(())
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn scan(&mut self, start: String, end: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let pairs = self.scan_bytes(start.as_bytes(), end.as_ref().map(String::as_bytes), limit)?;
+        into_string_pairs(pairs)
+    }
+
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let pairs = self.scan_prefix_bytes(prefix.as_bytes())?;
+        into_string_pairs(pairs)
+    }
+
+    // all sets and removes of the batch in one request, applied all or nothing
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request(&Request::BATCH(batch))?;
+        Ok(())
+    }
+
+    // run f against the server: reads go to the server right away, writes are buffered,
+    // then everything is sent in one request that applies the writes only if
+    // every key f read still has the value it saw, otherwise it fails with TransactionConflict
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn Transaction) -> Result<R>,
+    {
+        let mut txn = ClientTransaction {
+            client: self,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        };
+        let result = f(&mut txn)?;
+        let mut batch = WriteBatch::new();
+        for (key, value) in txn.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        let reads = txn.reads.into_iter().collect();
+        match txn.client.request(&Request::TXN(reads, batch))? {
+            Response::Conflict => Err(KVStoreError::TransactionConflict),
+            _ => Ok(result),
+        }
+    }
+
+    // add delta to the integer at key on the server, returns the new value
+    pub fn incr_bytes(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        match self.request(&Request::INCR(key.to_vec(), delta))? {
+            Response::Ok(Some(value)) => std::str::from_utf8(&value).ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or(KVStoreError::UnexpectedResponse),
+            _ => Err(KVStoreError::UnexpectedResponse),
+        }
+    }
+
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.incr_bytes(key.as_bytes(), delta)
+    }
+
+    // see KvsEngine::compare_and_swap_bytes, false when the key did not have the expected value
+    pub fn compare_and_swap_bytes(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        match self.request(&Request::CAS(key.to_vec(), expected.map(<[u8]>::to_vec), new.map(<[u8]>::to_vec)))? {
+            Response::Ok(_) => Ok(true),
+            Response::Conflict => Ok(false),
+            _ => Err(KVStoreError::UnexpectedResponse),
+        }
+    }
+
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        self.compare_and_swap_bytes(key.as_bytes(), expected.as_ref().map(String::as_bytes), new.as_ref().map(String::as_bytes))
+    }
+
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    pub fn set_if_equals(&mut self, key: String, expected: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), Some(value))
+    }
+
+    pub fn remove_if_equals(&mut self, key: String, expected: String) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    // have the server write a backup of its store into the directory name of its backup dir
+    // returns once the backup is complete
+    pub fn backup(&mut self, name: String) -> Result<()> {
+        self.request(&Request::BACKUP(name))?;
+        Ok(())
+    }
+
+    // after a timeout or any other io or codec error the response of the failed request
+    // may still arrive, so every later request fails with ConnectionBroken instead of reading it
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        if self.broken {
+            return Err(KVStoreError::ConnectionBroken);
+        }
+        let result = self.exchange(request);
+        if let Err(KVStoreError::IoError(_) | KVStoreError::CodecError(_)) = result {
+            self.broken = true;
+        }
+        result
+    }
+
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        // 把request序列化, 然后放进writer (or IO stream)
+        protocol::write_message(&mut self.writer, request)?;
+        //flush this output stream to server
+        self.writer.flush()?;
+
+        //server发过来的respone, errors of the engine come back as Response::Err
+        match protocol::read_message(&mut self.reader)? {
+            Response::Err(err) => Err(KVStoreError::ServerError(err)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn into_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs.into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
+}
+
+struct ClientTransaction<'a> {
+    client: &'a mut KvsClient,
+    // value of every key read from the server, checked again by the server at commit
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // None removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction for ClientTransaction<'_> {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key).or_else(|| self.reads.get(key)) {
+            return Ok(value.clone());
+        }
+        let value = self.client.get_bytes(key)?;
+        self.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+// Engine-neutral dumps of every key/value pair, to move data between KvStore and SledKvStore
+//
+// jsonl: one {"key": ..., "value": ..., "expires_at": ...} object per line, a key or value is a JSON string
+//        when it is UTF-8 and an array of its bytes otherwise, expires_at is left out for a pair without ttl
+// binary: DUMP_MAGIC, then per pair | key_len: u32 | key | value_len: u32 | value | expires_at: u64 |,
+//         little endian, expires_at 0 for a pair without ttl; DUMP_MAGIC_V1 dumps have no expires_at
+// expires_at is a unix time in milliseconds, so a key keeps the time it expires at across the move
+//
+// export writes the pairs in key order, import relies on that to resume after a checkpoint key
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use crate::engine::command;
+use serde::{Deserialize, Serialize};
+use crate::{KVStoreError, KvsEngine, Result, Snapshot, WriteBatch};
+
+const DUMP_MAGIC: &[u8; 8] = b"KVSDUMP2";
+// dumps written before they carried expiries, still imported
+const DUMP_MAGIC_V1: &[u8; 8] = b"KVSDUMP1";
+// import writes this many pairs per WriteBatch, both directions report progress this often
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Jsonl,
+    Binary,
+}
+
+// parses "jsonl" or "binary", as accepted by kvs-admin --format
+impl FromStr for DumpFormat {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<DumpFormat> {
+        match s {
+            "jsonl" => Ok(DumpFormat::Jsonl),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(KVStoreError::InvalidOption(format!("unknown dump format {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpFormat::Jsonl => write!(f, "jsonl"),
+            DumpFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+// a key or value in a jsonl dump
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data {
+        match String::from_utf8(bytes) {
+            Ok(text) => Data::Text(text),
+            Err(e) => Data::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+impl From<Data> for Vec<u8> {
+    fn from(data: Data) -> Vec<u8> {
+        match data {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPair {
+    key: Data,
+    value: Data,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+// a key, its value and its expiry
+type Pair = (Vec<u8>, Vec<u8>, Option<u64>);
+
+// a key as it is written in a jsonl dump, e.g. for the checkpoint key of the progress output
+pub fn display_key(key: &[u8]) -> String {
+    serde_json::to_string(&Data::from(key.to_vec())).expect("a key always serializes")
+}
+
+// the inverse of display_key, text that is not such a JSON value is taken as the key itself
+pub fn parse_key(s: &str) -> Vec<u8> {
+    serde_json::from_str::<Data>(s).map_or_else(|_| s.as_bytes().to_vec(), Vec::from)
+}
+
+struct DumpWriter<W: Write> {
+    writer: BufWriter<W>,
+    format: DumpFormat,
+}
+
+impl<W: Write> DumpWriter<W> {
+    fn new(writer: W, format: DumpFormat) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        if format == DumpFormat::Binary {
+            writer.write_all(DUMP_MAGIC)?;
+        }
+        Ok(DumpWriter { writer, format })
+    }
+
+    fn write(&mut self, (key, value, expires_at): Pair) -> Result<()> {
+        match self.format {
+            DumpFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, &JsonPair { key: key.into(), value: value.into(), expires_at })?;
+                self.writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                for data in [key, value] {
+                    let len = u32::try_from(data.len())
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "key or value over 4GiB"))?;
+                    self.writer.write_all(&len.to_le_bytes())?;
+                    self.writer.write_all(&data)?;
+                }
+                self.writer.write_all(&expires_at.unwrap_or(0).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// the pairs of a dump one by one, so a dump larger than the memory can be imported
+struct DumpReader<R: BufRead> {
+    reader: R,
+    format: DumpFormat,
+    // number of the line read last, for the errors of a jsonl dump
+    line: u64,
+    // false for a DUMP_MAGIC_V1 binary dump
+    has_expiries: bool,
+}
+
+impl<R: BufRead> DumpReader<R> {
+    fn new(mut reader: R, format: DumpFormat) -> Result<Self> {
+        if format == DumpFormat::Binary {
+            let mut magic = [0; DUMP_MAGIC.len()];
+            reader.read_exact(&mut magic)?;
+            if magic != *DUMP_MAGIC && magic != *DUMP_MAGIC_V1 {
+                return Err(KVStoreError::InvalidDump("not a binary dump".to_owned()));
+            }
+            let has_expiries = magic == *DUMP_MAGIC;
+            return Ok(DumpReader { reader, format, line: 0, has_expiries });
+        }
+        Ok(DumpReader { reader, format, line: 0, has_expiries: true })
+    }
+
+    fn next_pair(&mut self) -> Result<Option<Pair>> {
+        match self.format {
+            DumpFormat::Jsonl => loop {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                self.line += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair: JsonPair = serde_json::from_str(&line)
+                    .map_err(|e| KVStoreError::InvalidDump(format!("line {}: {}", self.line, e)))?;
+                return Ok(Some((pair.key.into(), pair.value.into(), pair.expires_at)));
+            },
+            DumpFormat::Binary => {
+                //a clean end of the dump is only allowed between two pairs
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let (key, value) = (self.read_data()?, self.read_data()?);
+                let mut expires_at = None;
+                if self.has_expiries {
+                    let mut bytes = [0; 8];
+                    self.reader.read_exact(&mut bytes)?;
+                    expires_at = Some(u64::from_le_bytes(bytes)).filter(|&expires_at| expires_at != 0);
+                }
+                Ok(Some((key, value, expires_at)))
+            }
+        }
+    }
+
+    fn read_data(&mut self) -> Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as u64;
+        //the length is unverified, so do not allocate it up front
+        let mut data = Vec::new();
+        self.reader.by_ref().take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(data)
+    }
+}
+
+impl<R: BufRead> Iterator for DumpReader<R> {
+    type Item = Result<Pair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair().transpose()
+    }
+}
+
+// write every pair of a snapshot of engine to writer with its expiry, the ones with a key after resume_after only
+// progress gets the number of pairs written and the last key of them, every CHECKPOINT_INTERVAL
+// pairs and once at the end; returns the number of pairs written
+pub fn export<E, W, P>(engine: &E, writer: W, format: DumpFormat, resume_after: Option<&[u8]>, mut progress: P) -> Result<u64>
+where
+    E: KvsEngine,
+    W: Write,
+    P: FnMut(u64, &[u8]),
+{
+    let snapshot = engine.snapshot()?;
+    let mut writer = DumpWriter::new(writer, format)?;
+    let mut count = 0;
+    let mut last_key = Vec::new();
+    for pair in snapshot.range_with_expiry(resume_after.unwrap_or(&[]), None) {
+        let pair = pair?;
+        if Some(&pair.0[..]) == resume_after {
+            continue;
+        }
+        last_key.clone_from(&pair.0);
+        writer.write(pair)?;
+        count += 1;
+        if count % CHECKPOINT_INTERVAL == 0 {
+            writer.flush()?;
+            progress(count, &last_key);
+        }
+    }
+    writer.flush()?;
+    progress(count, &last_key);
+    Ok(count)
+}
+
+// load the pairs of a dump into engine, the ones with a key after resume_after only
+// a pair keeps its expiry, one that has expired since the export is left out and not counted
+// progress is called like for export, once every pair up to the key it gets is flushed to the engine,
+// so an interrupted import can be resumed after that key
+pub fn import<E, R, P>(engine: &E, reader: R, format: DumpFormat, resume_after: Option<&[u8]>, mut progress: P) -> Result<u64>
+where
+    E: KvsEngine,
+    R: Read,
+    P: FnMut(u64, &[u8]),
+{
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    let mut last_key = Vec::new();
+    for pair in DumpReader::new(BufReader::new(reader), format)? {
+        let (key, value, expires_at) = pair?;
+        if resume_after.is_some_and(|resume_after| &key[..] <= resume_after) {
+            continue;
+        }
+        match expires_at {
+            Some(expires_at) if expires_at <= command::now_millis() => continue,
+            Some(expires_at) => batch.put_expiring(key.clone(), value, expires_at),
+            None => batch.put(key.clone(), value),
+        };
+        last_key = key;
+        count += 1;
+        if count % CHECKPOINT_INTERVAL == 0 {
+            engine.write_batch(mem::take(&mut batch))?;
+            engine.flush()?;
+            progress(count, &last_key);
+        }
+    }
+    engine.write_batch(batch)?;
+    engine.flush()?;
+    progress(count, &last_key);
+    Ok(count)
+}
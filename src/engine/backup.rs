@@ -0,0 +1,14 @@
+// Shared by the backup_to and restore of both engines
+use std::fs;
+use std::path::Path;
+use crate::{KVStoreError, Result};
+
+// a backup or a restored store never goes into a directory that holds anything already,
+// so it can not be mixed up with, or overwrite, the files of another store
+pub(crate) fn create_empty_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)?;
+    if fs::read_dir(path)?.next().is_some() {
+        return Err(KVStoreError::DirectoryNotEmpty(path.display().to_string()));
+    }
+    Ok(())
+}
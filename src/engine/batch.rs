@@ -0,0 +1,50 @@
+// A group of sets and removes applied together by KvsEngine::write_batch:
+// after a crash either all of them are visible or none
+use serde::{Deserialize, Serialize};
+use crate::Command;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct WriteBatch {
+    commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    // takes String and &str as well as raw bytes
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(Command::SET(key.into(), value.into()));
+        self
+    }
+
+    // expires_at is a unix time in milliseconds, as a dump carries it
+    pub(crate) fn put_expiring(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> &mut Self {
+        self.commands.push(Command::SET_TTL(key, value, expires_at));
+        self
+    }
+
+    // unlike KvsEngine::remove, deleting a missing key is not an error
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(Command::RM(key.into()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    // in the order they were added, a later command on the same key wins
+    pub(crate) fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    pub(crate) fn into_commands(self) -> Vec<Command> {
+        self.commands
+    }
+}
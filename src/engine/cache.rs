@@ -0,0 +1,103 @@
+// Size-bounded LRU cache in front of KvStore::get
+// an entry remembers the CommandPos its value was read from and only counts as a hit
+// while the index still points there, so a value replaced by a racing set is never served
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use lru::LruCache;
+use super::kv::CommandPos;
+
+// the keys are spread over independently locked shards so readers rarely wait on each other
+const SHARDS: usize = 16;
+
+// hit/miss counters of the value cache, see KvStore::cache_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub(crate) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    entries: LruCache<Vec<u8>, (CommandPos, Vec<u8>)>,
+    // bytes of keys and values held, at most capacity
+    size: usize,
+    capacity: usize,
+}
+
+impl ValueCache {
+    // capacity is in bytes of keys and values, split evenly between the shards
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        let shards = (0..SHARDS)
+            .map(|_| Mutex::new(Shard {
+                entries: LruCache::unbounded(),
+                size: 0,
+                capacity: capacity / SHARDS,
+            }))
+            .collect();
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // the cached value of key, if it was read from position
+    pub(crate) fn get(&self, key: &[u8], position: &CommandPos) -> Option<Vec<u8>> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = match shard.entries.get(key) {
+            Some((cached_position, value)) if cached_position == position => Some(value.clone()),
+            _ => None,
+        };
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, position: CommandPos, value: Vec<u8>) {
+        let entry_size = key.len() + value.len();
+        let mut shard = self.shard(&key).lock().unwrap();
+        if entry_size > shard.capacity {
+            return;
+        }
+        if let Some((old_key, (_, old_value))) = shard.entries.push(key, (position, value)) {
+            shard.size -= old_key.len() + old_value.len();
+        }
+        shard.size += entry_size;
+        while shard.size > shard.capacity {
+            match shard.entries.pop_lru() {
+                Some((old_key, (_, old_value))) => shard.size -= old_key.len() + old_value.len(),
+                None => break,
+            }
+        }
+    }
+
+    // drop the value of a key that was set, removed or moved by compaction
+    pub(crate) fn remove(&self, key: &[u8]) {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Some((_, value)) = shard.entries.pop(key) {
+            shard.size -= key.len() + value.len();
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
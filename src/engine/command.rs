@@ -2,10 +2,183 @@
 // command struct supports serial and deserial
 
 use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{KVStoreError, Result};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 
 pub enum Command {
-    SET(String, String),
-    RM(String),
-}
\ No newline at end of file
+    SET(Vec<u8>, Vec<u8>),
+    RM(Vec<u8>),
+    //a set whose key expires at the given unix time in milliseconds
+    SET_TTL(Vec<u8>, Vec<u8>, u64),
+}
+
+// On disk every command is one length-prefixed binary record, keys and values are raw bytes:
+// | crc32: u32 | key_len: u32 | value_len: u32 | flags: u8 | key | expires_at: u64 | value |
+// all integers are little endian, the crc covers everything after itself
+// an RM record is a tombstone: flag set and no value
+// the records of a write batch carry FLAG_BATCH except the last one, which commits the batch
+// expires_at is only there with FLAG_EXPIRES, value_len does not count it
+pub(crate) const HEADER_LEN: usize = 13;
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_BATCH: u8 = 2;
+const FLAG_EXPIRES: u8 = 4;
+
+// one record read back from a log file
+pub(crate) struct Record {
+    pub(crate) command: Command,
+    pub(crate) length: u64,
+    // more records of the same write batch follow this one
+    pub(crate) batch_continues: bool,
+}
+
+impl Command {
+    pub(crate) fn encode_in_batch(&self, batch_continues: bool) -> Vec<u8> {
+        let (key, value, mut flags) = match self {
+            Command::SET(key, value) => (key, &value[..], 0),
+            Command::SET_TTL(key, value, _) => (key, &value[..], FLAG_EXPIRES),
+            Command::RM(key) => (key, &[][..], FLAG_TOMBSTONE),
+        };
+        if batch_continues {
+            flags |= FLAG_BATCH;
+        }
+        let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + 8 + value.len());
+        //leave room for the crc, filled in once the rest is written
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.push(flags);
+        buf.extend_from_slice(key);
+        if let Some(expires_at) = self.expires_at() {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf.extend_from_slice(value);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // decode one whole record, e.g. the bytes a CommandPos points at
+    pub(crate) fn decode(buf: &[u8]) -> Result<Command> {
+        if buf.len() < HEADER_LEN {
+            return Err(KVStoreError::CorruptedRecord);
+        }
+        if buf.len() != HEADER_LEN + body_len(&buf[..HEADER_LEN]) {
+            return Err(KVStoreError::CorruptedRecord);
+        }
+        Command::from_parts(&buf[..HEADER_LEN], &buf[HEADER_LEN..])
+    }
+
+    // read the next record of a log file, None at the end of the file
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Option<Record>> {
+        let mut header = [0; HEADER_LEN];
+        //a clean end of file is only allowed between two records
+        let mut read = 0;
+        while read < HEADER_LEN {
+            match reader.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        //lengths come from an unverified header, so do not allocate them up front
+        let body_len = body_len(&header) as u64;
+        let mut body = Vec::new();
+        reader.by_ref().take(body_len).read_to_end(&mut body)?;
+        if body.len() as u64 != body_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let command = Command::from_parts(&header, &body)?;
+        Ok(Some(Record {
+            command,
+            length: (HEADER_LEN + body.len()) as u64,
+            batch_continues: header[12] & FLAG_BATCH != 0,
+        }))
+    }
+
+    fn from_parts(header: &[u8], body: &[u8]) -> Result<Command> {
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(body);
+        if hasher.finalize() != crc {
+            return Err(KVStoreError::CorruptedRecord);
+        }
+        let (key_len, _) = body_lens(header);
+        let key = body[..key_len].to_vec();
+        if header[12] & FLAG_TOMBSTONE != 0 {
+            Ok(Command::RM(key))
+        } else if header[12] & FLAG_EXPIRES != 0 {
+            let expires_at = u64::from_le_bytes(body[key_len..key_len + 8].try_into().unwrap());
+            Ok(Command::SET_TTL(key, body[key_len + 8..].to_vec(), expires_at))
+        } else {
+            Ok(Command::SET(key, body[key_len..].to_vec()))
+        }
+    }
+
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Command::SET(key, _) | Command::SET_TTL(key, _, _) | Command::RM(key) => key,
+        }
+    }
+
+    pub(crate) fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::SET_TTL(_, _, expires_at) => Some(*expires_at),
+            Command::SET(..) | Command::RM(_) => None,
+        }
+    }
+}
+
+// whether buf starts with a whole record whose crc verifies
+// open looks for one after a record that failed to decode, to tell damage from a torn tail
+pub(crate) fn starts_with_record(buf: &[u8]) -> bool {
+    if buf.len() < HEADER_LEN || buf[12] & !(FLAG_TOMBSTONE | FLAG_BATCH | FLAG_EXPIRES) != 0 {
+        return false;
+    }
+    let len = HEADER_LEN + body_len(&buf[..HEADER_LEN]);
+    len <= buf.len() && crc32fast::hash(&buf[4..len]).to_le_bytes() == buf[..4]
+}
+
+fn body_lens(header: &[u8]) -> (usize, usize) {
+    let key_len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let value_len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    (key_len as usize, value_len as usize)
+}
+
+//bytes after the header
+fn body_len(header: &[u8]) -> usize {
+    let (key_len, value_len) = body_lens(header);
+    let expires_len = if header[12] & FLAG_EXPIRES != 0 { 8 } else { 0 };
+    key_len + expires_len + value_len
+}
+
+// expiry times are unix times in milliseconds, compared against the wall clock
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+// a record copied out of its batch on its own, as compaction does, must not wait for the rest
+pub(crate) fn without_batch_flag(record: &[u8]) -> Cow<'_, [u8]> {
+    //a corrupted record keeps its bad crc instead of getting a fresh one
+    if record.len() < HEADER_LEN || record[12] & FLAG_BATCH == 0
+        || crc32fast::hash(&record[4..]).to_le_bytes() != record[..4]
+    {
+        return Cow::Borrowed(record);
+    }
+    let mut record = record.to_vec();
+    record[12] &= !FLAG_BATCH;
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Cow::Owned(record)
+}
@@ -0,0 +1,63 @@
+// Group commit: concurrent writers queue up, the one at the front becomes the leader and
+// commits everything queued with one append and one sync, then wakes the others with their results
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use crate::Result;
+
+// bounds how long the writers at the front of a long queue wait for the ones behind them
+const MAX_GROUP_SIZE: usize = 128;
+
+pub(crate) struct GroupCommit<W> {
+    queue: Mutex<VecDeque<Arc<Slot<W>>>>,
+    done: Condvar,
+}
+
+struct Slot<W> {
+    write: W,
+    result: Mutex<Option<Result<()>>>,
+}
+
+impl<W> GroupCommit<W> {
+    pub(crate) fn new() -> Self {
+        GroupCommit {
+            queue: Mutex::new(VecDeque::new()),
+            done: Condvar::new(),
+        }
+    }
+
+    // commit_group is only called by a leader, with the writes of its group in queue order,
+    // and returns one result per write
+    pub(crate) fn commit<F>(&self, write: W, commit_group: F) -> Result<()>
+    where
+        F: FnOnce(&[&W]) -> Vec<Result<()>>,
+    {
+        let slot = Arc::new(Slot { write, result: Mutex::new(None) });
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(Arc::clone(&slot));
+        loop {
+            if let Some(result) = slot.result.lock().unwrap().take() {
+                return result;
+            }
+            if Arc::ptr_eq(&queue[0], &slot) {
+                break;
+            }
+            queue = self.done.wait(queue).unwrap();
+        }
+
+        //the group stays queued while it is written, so writers arriving meanwhile wait for the next leader
+        let group: Vec<Arc<Slot<W>>> = queue.iter().take(MAX_GROUP_SIZE).cloned().collect();
+        drop(queue);
+        let writes: Vec<&W> = group.iter().map(|slot| &slot.write).collect();
+        let results = commit_group(&writes);
+
+        let mut queue = self.queue.lock().unwrap();
+        for (slot, result) in group.iter().zip(results) {
+            *slot.result.lock().unwrap() = Some(result);
+        }
+        queue.drain(..group.len());
+        self.done.notify_all();
+        drop(queue);
+        let result = slot.result.lock().unwrap().take();
+        result.expect("no result for the leader's own write")
+    }
+}
@@ -0,0 +1,111 @@
+// Bitcask-style hint files: data_{id}.hint lists where every record of the log
+// data_{id}.txt lives, so KvStore::open can rebuild the index without reading values.
+// Only compaction writes them, and a compacted log holds no tombstones,
+// so every hint entry stands for a set cmd.
+//
+// | data_len: u64 | entry ... | crc32: u32 |
+// entry: | key_len: u32 | file_id: u64 | offset: u64 | length: u64 | expires_at: u64 | key |
+// integers are little endian, the crc covers everything before it,
+// data_len is the size of the log file the hint was written for,
+// expires_at is the expiry of a set with a ttl and 0 for any other set
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::{KVStoreError, Result};
+
+const ENTRY_HEADER_LEN: usize = 4 + 8 + 8 + 8 + 8;
+
+pub(crate) struct HintEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    pub(crate) expires_at: Option<u64>,
+}
+
+fn hint_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("data_{}.hint", file_id))
+}
+
+// entries are (key, offset, length, expires_at) in the order of the records in the log
+pub(crate) fn write<'a>(
+    dir_path: &Path,
+    file_id: u64,
+    data_len: u64,
+    entries: impl Iterator<Item = (&'a [u8], u64, u64, Option<u64>)>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&data_len.to_le_bytes());
+    for (key, offset, length, expires_at) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&file_id.to_le_bytes());
+        buf.extend_from_slice(&offset.to_le_bytes());
+        buf.extend_from_slice(&length.to_le_bytes());
+        buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    //a half written hint is never visible under its real name
+    let tmp_path = dir_path.join(format!("data_{}.hint.tmp", file_id));
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, hint_path(dir_path, file_id))?;
+    Ok(())
+}
+
+// None if the log has no hint, an error if the hint can not be trusted
+pub(crate) fn load(dir_path: &Path, file_id: u64) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(hint_path(dir_path, file_id)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.len() < 8 + 4 {
+        return Err(KVStoreError::CorruptedHint);
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(KVStoreError::CorruptedHint);
+    }
+    //the log must be exactly the one the hint was written for
+    let data_len = u64::from_le_bytes(body[..8].try_into().unwrap());
+    if fs::metadata(dir_path.join(format!("data_{}.txt", file_id)))?.len() != data_len {
+        return Err(KVStoreError::CorruptedHint);
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &body[8..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return Err(KVStoreError::CorruptedHint);
+        }
+        let key_len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let entry_file_id = u64::from_le_bytes(rest[4..12].try_into().unwrap());
+        let offset = u64::from_le_bytes(rest[12..20].try_into().unwrap());
+        let length = u64::from_le_bytes(rest[20..28].try_into().unwrap());
+        let expires_at = u64::from_le_bytes(rest[28..36].try_into().unwrap());
+        rest = &rest[ENTRY_HEADER_LEN..];
+        if entry_file_id != file_id || rest.len() < key_len || offset + length > data_len {
+            return Err(KVStoreError::CorruptedHint);
+        }
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+        entries.push(HintEntry { key, offset, length, expires_at: (expires_at != 0).then_some(expires_at) });
+    }
+    Ok(Some(entries))
+}
+
+// called whenever the log itself is deleted
+pub(crate) fn remove(dir_path: &Path, file_id: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir_path, file_id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -1,57 +1,162 @@
-use std::cell::RefCell;
-use std::fs::{OpenOptions, remove_file, self};
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64,Ordering};
-use std::sync::{Arc,Mutex};
-use std::time::SystemTime;
+use std::fs::{OpenOptions, TryLockError, remove_file, self};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool,AtomicU64,Ordering};
+use std::sync::{mpsc,Arc,Mutex,Weak};
+use std::thread::{self,JoinHandle};
+use std::time::{Duration,SystemTime};
 use dashmap::DashMap;
-use log::{info,warn};
-use std::{collections::HashMap, collections::hash_map::Entry, fs::File};
-use std::io::{BufReader,Write, BufWriter, Seek, SeekFrom, self, Read, Take};
-use serde_json;
-use crate::KvsEngine;
-
-#[derive(Debug)]
-struct CommandPos {
+use dashmap::mapref::entry::Entry;
+use memmap2::Mmap;
+use log::{error,info,warn};
+use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufRead, BufReader,Write, BufWriter, Seek, SeekFrom, self};
+use crate::{KvsEngine,KvStoreOptions,ExpiringSnapshotIter,Snapshot,SyncPolicy,Transaction,WriteBatch};
+use super::manifest::{self, Manifest};
+use super::hint;
+use super::command;
+use super::cache::{CacheStats, ValueCache};
+use super::group_commit::GroupCommit;
+use super::transaction::KvTransaction;
+use super::kvs_engine::incremented;
+use super::snapshot;
+use super::backup;
+use super::legacy;
+
+//held locked by the open store, see lock_dir
+const LOCK_FILE: &str = "LOCK";
+//expired keys removed per hold of the writer lock, so a mass expiry does not stall the writes
+const SWEEP_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CommandPos {
     offset: u64,
     length: u64,
     file_id: u64,
+    //bumped by every write of the key since open, kept when compaction moves the record
+    //transactions compare it to find keys written after they read them
+    seq: u64,
+    //unix time in milliseconds, for a key set with a ttl
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    //an expired key is missing for every read, its record is stale
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 #[derive(Clone)]
 pub struct KvStore {
-    // key：String， vaule_metadata: CommandPos
-    index: Arc<DashMap<String, CommandPos>>,
+    // key: raw bytes， vaule_metadata: CommandPos
+    index: Arc<DashMap<Vec<u8>, CommandPos>>,
     current_readers: Reader,
     current_writer: Arc<Mutex<Writer>>,    
+    //None unless KvStoreOptions::value_cache_size is set
+    cache: Option<Arc<ValueCache>>,
+    group_commit: Arc<GroupCommit<PendingWrite>>,
+    //the flusher and sweeper threads, joined once the last clone is dropped
+    _background: Arc<Vec<Periodic>>,
+}
+
+//a set, remove, batch or transaction waiting in the group commit queue
+pub(crate) struct PendingWrite {
+    commands: Vec<Command>,
+    //KvsEngine::remove fails on a missing key, the deletes of a batch do not
+    strict_remove: bool,
+    //keys a transaction read with the seq they had, None for a missing key
+    read_seqs: Vec<(Vec<u8>, Option<u64>)>,
 }
 
 pub struct Writer {
     dir_path: Arc<PathBuf>,
-    current_readers: Reader,
     current_writer: BufWriterWithPos<File>,
     current_file_id: u64,
+    //stale bytes in the log files, reclaimed by compaction
     size_for_compaction: u64,
-    index: Arc<DashMap<String, CommandPos>>,
+    //all bytes in the log files
+    total_size: u64,
+    //seq of the last record written
+    last_seq: u64,
+    //(expires_at, key) of every key in the index with a ttl, so a sweep only visits the expired ones
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    index: Arc<DashMap<Vec<u8>, CommandPos>>,
+    //shared with the compactor, which replaces the sealed files in it
+    manifest: Arc<Mutex<Manifest>>,
+    //current_file_id as seen by the readers, every file before it is sealed
+    active_file_id: Arc<AtomicU64>,
+    cache: Option<Arc<ValueCache>>,
+    options: KvStoreOptions,
+    //written since the last sync, only tracked for the interval policy
+    unsynced: bool,
+    //set while the compactor works, so at most one compaction runs at a time
+    compacting: Arc<AtomicBool>,
+    //sends the id of the file to compact into to the compactor
+    compaction_sender: Option<mpsc::Sender<u64>>,
+    compactor: Option<JoinHandle<()>>,
+    //locked for as long as the store is open, released after the compactor is joined
+    _dir_lock: File,
+}
+
+//the background compaction thread
+//merges the live records of the sealed files (ids below the compaction file id) into one file
+struct Compactor {
+    dir_path: Arc<PathBuf>,
+    index: Arc<DashMap<Vec<u8>, CommandPos>>,
+    manifest: Arc<Mutex<Manifest>>,
+    readers: Reader,
+    compacting: Arc<AtomicBool>,
+    cache: Option<Arc<ValueCache>>,
 }
 
+//the open log files are shared by every clone and read with positional reads,
+//so no cursor is shared and a clone costs a few Arc increments
+#[derive(Clone)]
 pub struct Reader {
     dir_path: Arc<PathBuf>,
-    compaction_number: Arc<AtomicU64>,
-    readers: RefCell<HashMap<u64, BufReader<File>>>,
+    files: Arc<DashMap<u64, Arc<LogFile>>>,
+    //max number of open files in files
+    cache_size: usize,
+    active_file_id: Arc<AtomicU64>,
+    mmap_sealed_files: bool,
+    pins: Arc<Mutex<FilePins>>,
+}
+
+//log files snapshots still read from, compaction leaves them on disk until the last one is dropped
+#[derive(Default)]
+struct FilePins {
+    //number of live snapshots reading from each file
+    counts: HashMap<u64, usize>,
+    //pinned files a compaction is done with, deleted once unpinned
+    stale: HashSet<u64>,
 }
 
-impl Clone for Reader {
-    fn clone(&self) -> Self {
-        Reader {
-            dir_path: Arc::clone(&self.dir_path),
-            compaction_number: Arc::clone(&self.compaction_number),
-            readers: RefCell::new(HashMap::new()),
+impl FilePins {
+    fn pin(&mut self, file_ids: impl Iterator<Item = u64>) -> Vec<u64> {
+        let file_ids: BTreeSet<u64> = file_ids.collect();
+        for id in &file_ids {
+            *self.counts.entry(*id).or_default() += 1;
         }
+        file_ids.into_iter().collect()
     }
 }
 
-const MAX_COMPACTION_SIZE: u64 = 1024; 
+//a KvStore snapshot: a copy of the index as it was after the write with seq,
+//the values are read from the log files, which are pinned for it
+pub struct KvSnapshot {
+    entries: BTreeMap<Vec<u8>, CommandPos>,
+    readers: Reader,
+    seq: u64,
+    file_ids: Vec<u64>,
+}
+
+//an open log file, sealed files never change so they can be mapped once
+enum LogFile {
+    File(File),
+    Mmap(Mmap),
+}
+
 // BufWriterWithPos is a bufWriter and Position
 // design for getting the write offset quickly instead of using seek()
 // complete the Write trait for BufWriterwith Postion and write function as original write does not provide offset position
@@ -86,6 +191,14 @@ impl <T: Write + Seek> BufWriterWithPos<T> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    //flush the buffer, then force the file content from the page cache to the disk
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.bufwriter.flush()?;
+        self.bufwriter.get_ref().sync_data()
+    }
+}
+
 //impl Writer trait for BufWriterWithPos so that it can use Writer's methods defined in std::io and fs lib
 use crate::Command;
 impl <T: Write + Seek> Write for BufWriterWithPos<T> {
@@ -103,12 +216,12 @@ impl <T: Write + Seek> Write for BufWriterWithPos<T> {
 
 
 impl KvStore {
-    //read all validate the files in current dir to get the vector of sorted file_ids
-    fn sorted_file_ids(path: &Arc<PathBuf>) -> Result<Vec<u64>> {
+    //the ids of the data_{id}.txt files in the dir, sorted
+    //only the manifest says which of them are live, this is for cleanup and old directories
+    fn log_files_on_disk(path: &Path) -> Result<Vec<u64>> {
         //get the every filepath and dir in the directory
-        let pathbuf_list = fs::read_dir(path.as_path())?
-            .map(|res|res.map(|e|e.path()))
-            .flatten();
+        let pathbuf_list = fs::read_dir(path)?
+            .flat_map(|res|res.map(|e|e.path()));
         //filter filepath of all txt files
         //get the filenames
         //get the file_id from the filename
@@ -136,71 +249,109 @@ impl KvStore {
     //open(parameter)：impl Into<PathBuf> trait, which means that para in open func must be transferred to PathBuf
    
     pub fn open(open_path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(open_path, KvStoreOptions::default())
+    }
+
+    pub fn open_with(open_path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
         let dir_path = Arc::new(open_path.into());
         
-        fs::create_dir_all(&dir_path.as_path())?;
+        fs::create_dir_all(dir_path.as_path())?;
+        let dir_lock = lock_dir(&dir_path)?;
+        let manifest = open_manifest(&dir_path)?;
 
         let index =Arc::new(DashMap::new());
-        let mut readers = HashMap::new();
         // how to get current_file_id and current compaction_size
         // Update index and current_reader，as they have file_id mapping
-        // Traverse all existing logfiles
-        let file_ids = KvStore::sorted_file_ids(&dir_path)?;
-        let mut current_file_id= 0;
+        // Traverse the live logfiles, the manifest always lists at least the active one
+        let file_ids = manifest.files.clone();
+        let current_file_id = *file_ids.last().expect("manifest without an active file");
         
-        if let Some(id) = file_ids.last() {
-            current_file_id = *id;
-        }
-        
-        let mut size_for_compaction = 0;
+        let mut stats = LoadStats::default();
         /*
         * 1.recreate the currennt reader: file_id, bufreader
         * 2.recreate the index: key id, Cmdpos - offset + length + file_id
         */
 
-        //1) When no logs on the disk，current_file_id is 0.
-        //2) and now current_file_id is 0,file_ids vec is empty，this following block will be passed
+        let newest_file_id = file_ids.last().copied();
+        //keys already expired are dropped while loading, like by the sweeper
+        let now = command::now_millis();
         for id in file_ids {
             let file_path = dir_path.join(format!("data_{}.txt", id));
-            //open the each file into bufreader
-            let reader = BufReader::new(File::open(&file_path)?);
-            //1.Update the reader list
-            readers.insert(id, reader);
+
+            //a compacted log comes with a hint, which gives the index without reading the values
+            match hint::load(&dir_path, id) {
+                Ok(Some(entries)) => {
+                    for entry in entries {
+                        let position = CommandPos {
+                            offset: entry.offset,
+                            length: entry.length,
+                            file_id: id,
+                            seq: stats.next_seq(),
+                            expires_at: entry.expires_at,
+                        };
+                        stats.set(&index, entry.key, position, now);
+                    }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("hint of {:?} is unusable ({}), replaying the log", file_path, e),
+            }
             
-            //deserliaze the files on disk
-            //split the command: into_iter to convert the deserialized commands to iter
-            let mut des_iter = serde_json::Deserializer::from_reader(BufReader::new(File::open(&file_path)?)).into_iter::<Command>();
+            //decode the records on disk one by one
+            let mut record_reader = BufReader::new(File::open(&file_path)?);
             
-            let mut offset0 = des_iter.byte_offset() as u64;//bytes which have been deserialized
+            let mut offset0 = 0;//bytes which have been decoded
+            //records of a write batch are only applied once its last record is read
+            let mut batch = Vec::new();
+            let mut batch_start = 0;
+            let mut torn = false;
             
-            while let Some(command) = des_iter.next() {
-                let offset1 = des_iter.byte_offset() as u64;
-                //length of each command
-                let val_length = offset1 - offset0;
-                
-                match command? { 
-                    Command::SET(key,_ ) => {
-                        index.insert(key, 
-                            CommandPos{
-                                offset: offset0,
-                                length: val_length,
-                                file_id: id,
-                            }
-                        );
-                        //TODO：if the key exist, + compaction size, if not NOT +
-                        size_for_compaction += val_length;
+            loop {
+                let record = match Command::read_from(&mut record_reader) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    //only the newest file can have been cut by a crash in the middle of Writer::set/remove
+                    Err(e) if Some(id) == newest_file_id && is_torn_tail(&e, &mut record_reader, &file_path, offset0)? => {
+                        torn = true;
+                        break;
                     }
-                    Command::RM(key) => {
-                        //set cmd length
-                        let size_pre_setcmd = index.remove(&key).map(|(_,p)|p.length).unwrap_or(0);
-                        size_for_compaction += size_pre_setcmd; 
-                        
-                        //rm cmd length
-                        size_for_compaction += val_length;
-
+                    Err(e) => {
+                        error!("can not recover {:?} at offset {}: {}", file_path, offset0, e);
+                        return Err(e);
                     }
                 };
-                offset0 = offset1;
+                if batch.is_empty() {
+                    batch_start = offset0;
+                }
+                let expires_at = record.command.expires_at();
+                batch.push((record.command, CommandPos {
+                    offset: offset0,
+                    length: record.length,
+                    file_id: id,
+                    seq: stats.next_seq(),
+                    expires_at,
+                }));
+                offset0 += record.length;
+                if record.batch_continues {
+                    continue;
+                }
+                
+                for (command, position) in batch.drain(..) {
+                    match command { 
+                        Command::SET(key,_ ) | Command::SET_TTL(key, _, _) => stats.set(&index, key, position, now),
+                        Command::RM(key) => stats.remove(&index, &key, position.length),
+                    };
+                }
+            }
+
+            //a batch without its last record was cut by a crash as well, it is dropped as a whole
+            if torn || !batch.is_empty() {
+                if Some(id) != newest_file_id {
+                    error!("{:?} ends in the middle of a write batch", file_path);
+                    return Err(KVStoreError::CorruptedRecord);
+                }
+                truncate_torn_tail(&file_path, if batch.is_empty() { offset0 } else { batch_start })?;
             }
         }
         //To initialize current_writer, need to get the current_file_id firstly
@@ -213,250 +364,1035 @@ impl KvStore {
                 .append(true)
                 .open(&current_file_path)?,
             )?;
-        
-            //3) once the loop has been passed, and current_writer has created a file named data_0.txt
-            //4) update log file whose file_id == 0 in reader
-            if current_file_id == 0 {
-                    readers.insert(
-                    current_file_id,
-                    BufReader::new(File::open(&current_file_path)?),
-                );
-            }
+        //files are opened on the first read
+        let active_file_id = Arc::new(AtomicU64::new(current_file_id));
         let current_readers = Reader {
             dir_path: Arc::clone(&dir_path),
-            compaction_number: Arc::new(AtomicU64::new(0)),
-            readers: RefCell::new(readers),
+            files: Arc::new(DashMap::new()),
+            cache_size: options.reader_cache_size,
+            active_file_id: Arc::clone(&active_file_id),
+            mmap_sealed_files: options.mmap_sealed_files,
+            pins: Arc::new(Mutex::new(FilePins::default())),
         };  
 
+        let manifest = Arc::new(Mutex::new(manifest));
+        let cache = (options.value_cache_size > 0)
+            .then(|| Arc::new(ValueCache::new(options.value_cache_size)));
+        let compacting = Arc::new(AtomicBool::new(false));
+        let (compaction_sender, compaction_receiver) = mpsc::channel();
+        let compactor = Compactor {
+            dir_path: Arc::clone(&dir_path),
+            index: Arc::clone(&index),
+            manifest: Arc::clone(&manifest),
+            readers: current_readers.clone(),
+            compacting: Arc::clone(&compacting),
+            cache: cache.clone(),
+        }.spawn(compaction_receiver)?;
+
         let current_writer = Arc::new(Mutex::new(
             Writer {
                 dir_path,
-                current_readers: current_readers.clone(),
                 current_writer,
                 current_file_id,
-                size_for_compaction,
+                size_for_compaction: stats.size_for_compaction,
+                total_size: stats.total_size,
+                last_seq: stats.next_seq,
+                expiries: stats.expiries,
                 index:Arc::clone(&index),
+                manifest,
+                active_file_id,
+                cache: cache.clone(),
+                options: options.clone(),
+                unsynced: false,
+                compacting,
+                compaction_sender: Some(compaction_sender),
+                compactor: Some(compactor),
+                _dir_lock: dir_lock,
             }
         ));
+        let mut background = vec![spawn_expiry_sweeper(Arc::downgrade(&current_writer), options.expiry_sweep_interval)?];
+        if let SyncPolicy::Interval(interval) = options.sync_policy {
+            background.push(spawn_interval_flusher(Arc::downgrade(&current_writer), interval)?);
+        }
         
         let store = KvStore {
             index,
             current_readers,
             current_writer,
+            cache,
+            group_commit: Arc::new(GroupCommit::new()),
+            _background: Arc::new(background),
         };
 
         Ok(store)
     }
+
+    //open a copy of a backup written by backup_to at open_path, which must be empty
+    pub fn restore(backup_path: impl Into<PathBuf>, open_path: impl Into<PathBuf>) -> Result<KvStore> {
+        let backup_path = backup_path.into();
+        let open_path = open_path.into();
+        if !backup_path.join(manifest::MANIFEST_FILE).is_file() {
+            return Err(KVStoreError::InvalidBackup(backup_path.display().to_string()));
+        }
+        backup::create_empty_dir(&open_path)?;
+
+        let mut file_names = Vec::new();
+        for entry in fs::read_dir(&backup_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                file_names.push(entry.file_name());
+            }
+        }
+        //the MANIFEST last, like backup_to writes it
+        file_names.sort_by_key(|name| name == manifest::MANIFEST_FILE);
+        for name in file_names {
+            let target = open_path.join(&name);
+            fs::copy(backup_path.join(&name), &target)?;
+            File::open(&target)?.sync_all()?;
+        }
+        manifest::sync_dir(&open_path)?;
+        KvStore::open(open_path)
+    }
 }
 
-impl KvsEngine for KvStore {
-    fn set(& self, key: String, value: String) -> Result<()> {
-      self.current_writer.lock().unwrap().set(key, value)?;
-      Ok(())
+//the counters of the Writer, rebuilt together with the index while the logs are loaded
+#[derive(Default)]
+struct LoadStats {
+    size_for_compaction: u64,
+    total_size: u64,
+    next_seq: u64,
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl LoadStats {
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    //the overwritten set cmd becomes stale
+    //an expired one overwrites the key as well, but then is stale itself like a rm cmd
+    fn set(&mut self, index: &DashMap<Vec<u8>, CommandPos>, key: Vec<u8>, position: CommandPos, now: u64) {
+        if position.is_expired(now) {
+            return self.remove(index, &key, position.length);
+        }
+        self.total_size += position.length;
+        match index.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(position);
+                replace_expiry(&mut self.expiries, entry.key(), old.expires_at, position.expires_at);
+                self.size_for_compaction += old.length;
+            }
+            Entry::Vacant(entry) => {
+                replace_expiry(&mut self.expiries, entry.key(), None, position.expires_at);
+                entry.insert(position);
+            }
+        }
+    }
+
+    //both the removed set cmd and the rm cmd itself are stale
+    fn remove(&mut self, index: &DashMap<Vec<u8>, CommandPos>, key: &[u8], length: u64) {
+        self.total_size += length;
+        if let Some((_, old)) = index.remove(key) {
+            replace_expiry(&mut self.expiries, key, old.expires_at, None);
+            self.size_for_compaction += old.length;
+        }
+        self.size_for_compaction += length;
+    }
+}
+
+//keep the expiries of the Writer in step with an index entry of key whose expiry changes from old to new
+fn replace_expiry(expiries: &mut BTreeSet<(u64, Vec<u8>)>, key: &[u8], old: Option<u64>, new: Option<u64>) {
+    if old == new {
+        return;
+    }
+    if let Some(old) = old {
+        expiries.remove(&(old, key.to_vec()));
+    }
+    if let Some(new) = new {
+        expiries.insert((new, key.to_vec()));
+    }
+}
+
+//two processes appending to the same log would corrupt it, e.g. kvs-admin next to a running kvs-server,
+//so the directory is locked while a store has it open, like sled does with its own db
+fn lock_dir(dir_path: &Path) -> Result<File> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(dir_path.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KVStoreError::StoreLocked(dir_path.display().to_string())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+//load the manifest, finishing an interrupted compaction first
+//a new directory, or one from before the manifest listed the files, gets one from the disk
+//and has its legacy JSON logs converted
+fn open_manifest(dir_path: &Path) -> Result<Manifest> {
+    let mut manifest = Manifest::load(dir_path)?;
+    recover_compaction(dir_path, &mut manifest)?;
+
+    if manifest.format_version == 0 {
+        let mut files = KvStore::log_files_on_disk(dir_path)?;
+        if files.is_empty() {
+            OpenOptions::new().create(true).append(true).open(dir_path.join("data_0.txt"))?;
+            files.push(0);
+        }
+        //the store may even predate the binary records
+        for id in &files {
+            if legacy::is_legacy_log(&dir_path.join(format!("data_{}.txt", id)))? {
+                legacy::convert(dir_path, *id)?;
+            }
+        }
+        manifest.format_version = manifest::FORMAT_VERSION;
+        manifest.files = files;
+        manifest.store(dir_path)?;
+    } else if manifest.format_version < manifest::FORMAT_VERSION {
+        //the logs of older versions read fine, but their hints have a shorter entry layout
+        for id in &manifest.files {
+            hint::remove(dir_path, *id)?;
+        }
+        info!("upgrading the store from format version {} to {}", manifest.format_version, manifest::FORMAT_VERSION);
+        manifest.format_version = manifest::FORMAT_VERSION;
+        manifest.store(dir_path)?;
     }
 
-    fn get(& self, key: String) -> Result<Option<String>> {
-        if let Some (entry) = self.index.get(&key) {
-            self.current_readers.read_command(entry.value())
+    let first_live_id = manifest.files.first().copied().unwrap_or(0);
+    for id in KvStore::log_files_on_disk(dir_path)? {
+        if manifest.files.contains(&id) {
+            continue;
+        }
+        //a compacted file kept for a snapshot when the process stopped, the compaction has replaced it
+        if id < first_live_id {
+            info!("removing data_{}.txt, it was compacted already", id);
+            remove_file(dir_path.join(format!("data_{}.txt", id)))?;
         } else {
-            Ok(None)
+            warn!("ignoring data_{}.txt, it is not a live log file", id);
         }
     }
-    fn remove(& self, key: String) -> Result<()> {
-        self.current_writer.lock().unwrap().remove(key)?;
-        Ok(())
+    Ok(manifest)
+}
+
+//finish or roll back a compaction interrupted by a crash, before the log files are replayed
+fn recover_compaction(dir_path: &Path, manifest: &mut Manifest) -> Result<()> {
+    if let Some(compaction_file_id) = manifest.pending_compaction {
+        //the compacted file was complete: finish the rename and the deletions
+        let tmp_path = dir_path.join(format!("data_{}.txt.tmp", compaction_file_id));
+        if tmp_path.exists() {
+            fs::rename(&tmp_path, dir_path.join(format!("data_{}.txt", compaction_file_id)))?;
+        }
+        for id in KvStore::log_files_on_disk(dir_path)? {
+            if id < compaction_file_id {
+                remove_file(dir_path.join(format!("data_{}.txt", id)))?;
+                hint::remove(dir_path, id)?;
+            }
+        }
+        manifest::sync_dir(dir_path)?;
+        warn!("finished the interrupted compaction into data_{}.txt", compaction_file_id);
+        manifest.pending_compaction = None;
+        manifest.store(dir_path)?;
+    }
+    //a compaction that never reached the manifest left only a temp file, the old files are intact
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        let is_compaction_tmp = path.extension() == Some("tmp".as_ref())
+            && path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("data_"));
+        if is_compaction_tmp {
+            warn!("removing {:?} left by an interrupted compaction", path);
+            remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// a record that failed to decode is a torn tail when it is the last thing in the file:
+// either its checksum fails and nothing follows it, or the file ends inside it
+// a damaged length runs past the end of the file as well, so that only counts as torn
+// when no valid record follows, or the records behind it would be cut off with the tail
+fn is_torn_tail(err: &KVStoreError, record_reader: &mut BufReader<File>, file_path: &Path, offset: u64) -> Result<bool> {
+    match err {
+        KVStoreError::IoError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            if valid_record_after(file_path, offset)? {
+                error!("damaged record length in {:?} at offset {}, valid records follow it", file_path, offset);
+                return Err(KVStoreError::CorruptedRecord);
+            }
+            Ok(true)
+        }
+        KVStoreError::CorruptedRecord => Ok(record_reader.fill_buf()?.is_empty()),
+        _ => Ok(false),
+    }
+}
+
+// whether a record with a valid checksum starts anywhere after offset
+fn valid_record_after(file_path: &Path, offset: u64) -> Result<bool> {
+    let file = File::open(file_path)?;
+    //safety: nothing writes the log before open has finished replaying it
+    let data = unsafe { Mmap::map(&file)? };
+    let start = (offset as usize + 1).min(data.len());
+    Ok((start..data.len()).any(|at| command::starts_with_record(&data[at..])))
+}
+
+//drop everything after the last good record so the writer appends right behind it
+fn truncate_torn_tail(file_path: &Path, good_len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(file_path)?;
+    let file_len = file.metadata()?.len();
+    warn!("torn record at the end of {:?}, truncating it from {} to {} bytes ({} bytes dropped)",
+        file_path, file_len, good_len, file_len - good_len);
+    file.set_len(good_len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+//a background thread calling tick every interval, stopped and joined once dropped
+//it only holds a Weak of the writer, which a tick upgrades for its duration, so joining it
+//makes sure the writer, and the lock of the directory with it, is gone once the last KvStore is
+struct Periodic {
+    //never sent on, dropping it stops the thread
+    stop_sender: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    fn spawn(name: &str, interval: Duration, mut tick: impl FnMut() + Send + 'static) -> Result<Periodic> {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    tick();
+                }
+            })?;
+        Ok(Periodic { stop_sender: Some(stop_sender), handle: Some(handle) })
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background thread panicked");
+            }
+        }
+    }
+}
+
+//background flusher of SyncPolicy::Interval
+fn spawn_interval_flusher(writer: Weak<Mutex<Writer>>, interval: Duration) -> Result<Periodic> {
+    Periodic::spawn("kvs-flusher", interval, move || {
+        let Some(writer) = writer.upgrade() else { return };
+        let mut writer = writer.lock().unwrap();
+        if writer.unsynced {
+            if let Err(e) = writer.current_writer.sync_data() {
+                error!("background flusher can not sync the log: {}", e);
+                return;
+            }
+            writer.unsynced = false;
+        }
+    })
+}
+
+//removes the index entries of expired keys, SWEEP_BATCH of them per hold of the writer lock
+fn spawn_expiry_sweeper(writer: Weak<Mutex<Writer>>, interval: Duration) -> Result<Periodic> {
+    Periodic::spawn("kvs-sweeper", interval, move || {
+        let Some(writer) = writer.upgrade() else { return };
+        loop {
+            let mut writer = writer.lock().unwrap();
+            let more = writer.remove_expired(SWEEP_BATCH);
+            if let Err(e) = writer.after_write() {
+                error!("can not roll or compact the log: {}", e);
+            }
+            if !more {
+                break;
+            }
+        }
+    })
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvSnapshot;
+
+    fn set_bytes(& self, key: &[u8], value: &[u8]) -> Result<()> {
+        let command = Command::SET(key.to_vec(), value.to_vec());
+        self.commit(PendingWrite { commands: vec![command], strict_remove: false, read_seqs: Vec::new() })
+    }
+
+    fn get_bytes(& self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_versioned(key)?.map(|(value, _)| value))
+    }
+
+    fn remove_bytes(& self, key: &[u8]) -> Result<()> {
+        self.commit(PendingWrite { commands: vec![Command::RM(key.to_vec())], strict_remove: true, read_seqs: Vec::new() })
+    }
+
+    fn scan_bytes(& self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self.index.iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| &key[..] >= start && end.is_none_or(|end| &key[..] < end))
+            .collect();
+        self.read_sorted(keys, limit)
+    }
+
+    fn scan_prefix_bytes(& self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let keys = self.index.iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        self.read_sorted(keys, usize::MAX)
+    }
+
+    fn set_with_ttl_bytes(& self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let command = Command::SET_TTL(key.to_vec(), value.to_vec(), command::expires_at(ttl));
+        self.commit(PendingWrite { commands: vec![command], strict_remove: false, read_seqs: Vec::new() })
+    }
+
+    fn transaction<F, R>(& self, f: F) -> Result<R>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<R>,
+    {
+        let mut txn = KvTransaction::new(self);
+        let result = f(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    //takes the writer lock itself instead of queuing for a group commit,
+    //so no other write can come between the comparison and the swap
+    fn compare_and_swap_bytes(& self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        let mut writer = self.current_writer.lock().unwrap();
+        if self.get_bytes(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let command = match new {
+            Some(value) => Command::SET(key.to_vec(), value.to_vec()),
+            None if expected.is_none() => return Ok(true),
+            None => Command::RM(key.to_vec()),
+        };
+        let write = PendingWrite { commands: vec![command], strict_remove: false, read_seqs: Vec::new() };
+        writer.write_group(&[&write]).pop().unwrap()?;
+        Ok(true)
+    }
+
+    //under the writer lock like compare_and_swap
+    fn incr_bytes(& self, key: &[u8], delta: i64) -> Result<i64> {
+        let mut writer = self.current_writer.lock().unwrap();
+        let value = incremented(key, self.get_bytes(key)?.as_deref(), delta)?;
+        let command = Command::SET(key.to_vec(), value.to_string().into_bytes());
+        let write = PendingWrite { commands: vec![command], strict_remove: false, read_seqs: Vec::new() };
+        writer.write_group(&[&write]).pop().unwrap()?;
+        Ok(value)
+    }
+
+    fn write_batch(& self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.commit(PendingWrite { commands: batch.into_commands(), strict_remove: false, read_seqs: Vec::new() })
+    }
+
+    //written from a snapshot, so the store keeps serving and compacting meanwhile
+    fn backup_to(& self, backup_path: impl Into<PathBuf>) -> Result<()> {
+        self.snapshot()?.write_backup(&backup_path.into())
+    }
+
+    //the index is copied under the writer lock, so the snapshot never sees part of a batch
+    //only keys and positions are copied, the values stay in the log files
+    //that is still time and memory in the number of keys, and every write waits for the copy,
+    //while the snapshot lives compaction can not delete the files it pins either
+    fn snapshot(& self) -> Result<KvSnapshot> {
+        let writer = self.current_writer.lock().unwrap();
+        let now = command::now_millis();
+        //taken before the copy, so the compactor can not delete a file the copy points into
+        let mut pins = self.current_readers.pins.lock().unwrap();
+        let entries: BTreeMap<Vec<u8>, CommandPos> = self.index.iter()
+            .filter(|entry| !entry.value().is_expired(now))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let file_ids = pins.pin(entries.values().map(|position| position.file_id));
+        drop(pins);
+        Ok(KvSnapshot {
+            entries,
+            readers: self.current_readers.clone(),
+            seq: writer.last_seq,
+            file_ids,
+        })
+    }
+
+    fn flush(& self) -> Result<()> {
+        self.current_writer.lock().unwrap().flush()
+    }
+}
+
+impl KvStore {
+    //the leader of a group takes the writer lock for all writes queued with it
+    fn commit(&self, write: PendingWrite) -> Result<()> {
+        self.group_commit.commit(write, |group| self.current_writer.lock().unwrap().write_group(group))
+    }
+
+    //value of key together with the seq of the record it was read from
+    pub(crate) fn read_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            //copy the position out, so the index shard is not locked during the read
+            let position = match self.index.get(key) {
+                Some(entry) if !entry.is_expired(command::now_millis()) => *entry.value(),
+                _ => return Ok(None),
+            };
+            if let Some(value) = self.cache.as_ref().and_then(|cache| cache.get(key, &position)) {
+                return Ok(Some((value, position.seq)));
+            }
+            match self.current_readers.read_command(&position) {
+                //a compaction moved the record and deleted its file in the meantime, look again
+                Err(KVStoreError::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound
+                    && self.index.get(key).map(|entry| *entry.value()) != Some(position) => continue,
+                Ok(Some(value)) => {
+                    if let Some(cache) = &self.cache {
+                        cache.insert(key.to_vec(), position, value.clone());
+                    }
+                    return Ok(Some((value, position.seq)));
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    //seq of the live record of key, None when the key is missing
+    pub(crate) fn current_seq(&self, key: &[u8]) -> Option<u64> {
+        self.index.get(key)
+            .filter(|entry| !entry.is_expired(command::now_millis()))
+            .map(|entry| entry.value().seq)
+    }
+
+    //applied only if every key in read_seqs still has the seq it was read at
+    pub(crate) fn commit_transaction(&self, commands: Vec<Command>, read_seqs: Vec<(Vec<u8>, Option<u64>)>) -> Result<()> {
+        self.commit(PendingWrite { commands, strict_remove: false, read_seqs })
+    }
+
+    //hits and misses of the value cache since open, None when it is off
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    //the index is unordered, so scans sort a snapshot of the matching keys
+    //values are read through get_bytes(), a key removed since the snapshot is skipped
+    fn read_sorted(&self, mut keys: Vec<Vec<u8>>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        keys.sort_unstable();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if pairs.len() >= limit {
+                break;
+            }
+            if let Some(value) = self.get_bytes(&key)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+impl KvSnapshot {
+    //seq of the last write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    //the pairs of the snapshot as a store directory holding one compacted log and its hint
+    //the MANIFEST goes last, a directory without it is an unfinished backup
+    fn write_backup(&self, backup_path: &Path) -> Result<()> {
+        backup::create_empty_dir(backup_path)?;
+        let mut writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(backup_path.join("data_0.txt"))?,
+        )?;
+        let mut positions = Vec::with_capacity(self.entries.len());
+        for (key, position) in &self.entries {
+            positions.push((key, self.readers.copy_record(position, &mut writer, 0)?));
+        }
+        writer.sync_data()?;
+        let data_len = writer.get_position();
+        drop(writer);
+
+        let hint_entries = positions.iter()
+            .map(|(key, position)| (&key[..], position.offset, position.length, position.expires_at));
+        hint::write(backup_path, 0, data_len, hint_entries)?;
+        Manifest {
+            format_version: manifest::FORMAT_VERSION,
+            files: vec![0],
+            ..Manifest::default()
+        }.store(backup_path)
+    }
+}
+
+impl Snapshot for KvSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.entries.get(key) {
+            Some(position) => self.readers.read_command(position),
+            None => Ok(None),
+        }
+    }
+
+    fn range_with_expiry(&self, start: &[u8], end: Option<&[u8]>) -> ExpiringSnapshotIter<'_> {
+        Box::new(snapshot::range(&self.entries, start, end).filter_map(|(key, position)| {
+            self.readers.read_command(position)
+                .map(|value| value.map(|value| (key.clone(), value, position.expires_at)))
+                .transpose()
+        }))
+    }
+}
+
+impl Drop for KvSnapshot {
+    fn drop(&mut self) {
+        self.readers.unpin(&self.file_ids);
     }
 }
 
 impl Writer {    
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let this_command = Command::SET(key.clone(), value);
-        //to vec as write_all receives a [u8] buf
-        let serialized_command = serde_json::to_vec(&this_command)?; 
-        //store the previous offset
-        let offset0 = self.current_writer.get_position(); 
-        //write to which file? -(1)
-        //initialize the struct current writer
-        self.current_writer.write_all(&serialized_command)?;
+    //one append and one sync for the records of every write in the group
+    //a batch keeps FLAG_BATCH on all its records but the last, so it stays all or nothing
+    fn write_group(&mut self, group: &[&PendingWrite]) -> Vec<Result<()>> {
+        //seq of a key once the writes before it in the group are applied, None if removed
+        let mut written: HashMap<&[u8], Option<u64>> = HashMap::new();
+        let now = command::now_millis();
+        let current_seq = |written: &HashMap<&[u8], Option<u64>>, key: &[u8]| match written.get(key) {
+            Some(seq) => *seq,
+            None => self.index.get(key).filter(|entry| !entry.is_expired(now)).map(|entry| entry.seq),
+        };
+        let mut results = Vec::with_capacity(group.len());
+        let mut serialized_group = Vec::new();
+        let mut records = Vec::new();
+        for write in group {
+            if write.strict_remove {
+                let missing = write.commands.iter().any(|command| match command {
+                    Command::RM(key) => current_seq(&written, key).is_none(),
+                    Command::SET(..) | Command::SET_TTL(..) => false,
+                });
+                if missing {
+                    results.push(Err(KVStoreError::KeyNotFound));
+                    continue;
+                }
+            }
+            //a transaction only commits if nothing it read was written since
+            if write.read_seqs.iter().any(|(key, seq)| current_seq(&written, key) != *seq) {
+                results.push(Err(KVStoreError::TransactionConflict));
+                continue;
+            }
+            for (i, command) in write.commands.iter().enumerate() {
+                let record = command.encode_in_batch(i + 1 < write.commands.len());
+                self.last_seq += 1;
+                records.push((command, record.len() as u64, self.last_seq));
+                serialized_group.extend_from_slice(&record);
+                match command {
+                    Command::SET(key, _) | Command::SET_TTL(key, _, _) => written.insert(key, Some(self.last_seq)),
+                    Command::RM(key) => written.insert(key, None),
+                };
+            }
+            results.push(Ok(()));
+        }
+        if records.is_empty() {
+            return results;
+        }
+
+        if let Err(e) = self.append(&serialized_group, &records) {
+            error!("can not write {} records to data_{}.txt: {}", records.len(), self.current_file_id, e);
+            //every write of the group shares the failure
+            return results.into_iter()
+                .map(|result| result.and_then(|()| Err(io::Error::new(e.kind(), e.to_string()).into())))
+                .collect();
+        }
+        //the group is on disk already, a failed roll or compaction is tried again after the next write
+        if let Err(e) = self.after_write() {
+            error!("can not roll or compact the log: {}", e);
+        }
+        results
+    }
+
+    //write the records, then point the index at them
+    fn append(&mut self, serialized: &[u8], records: &[(&Command, u64, u64)]) -> io::Result<()> {
+        let mut offset = self.current_writer.get_position();
+        self.current_writer.write_all(serialized)?;
         self.current_writer.flush()?;
+        self.sync_after_write()?;
 
-        // get the new offset
-        let offset1 = self.current_writer.get_position();
-        let length = offset1 - offset0;
-        //update the index
-        //key was supposed to have been moved
-        self.index.insert(key, 
-            CommandPos { 
-                offset: offset0, 
-                length: length, 
-                file_id: self.current_file_id, 
-            }
-        );
-        self.size_for_compaction += length;
+        for (command, length, seq) in records {
+            let position = CommandPos {
+                offset,
+                length: *length,
+                file_id: self.current_file_id,
+                seq: *seq,
+                expires_at: command.expires_at(),
+            };
+            offset += length;
+            self.total_size += length;
+            match command {
+                //the overwritten set cmd becomes stale
+                Command::SET(key, _) | Command::SET_TTL(key, _, _) => {
+                    self.invalidate_cached(key);
+                    let old = self.index.insert(key.clone(), position);
+                    replace_expiry(&mut self.expiries, key, old.and_then(|old| old.expires_at), position.expires_at);
+                    if let Some(old) = old {
+                        self.size_for_compaction += old.length;
+                    }
+                }
+                //both the removed set cmd and the rm cmd itself are stale
+                Command::RM(key) => {
+                    self.invalidate_cached(key);
+                    if let Some((_, old)) = self.index.remove(key) {
+                        replace_expiry(&mut self.expiries, key, old.expires_at, None);
+                        self.size_for_compaction += old.length;
+                    }
+                    self.size_for_compaction += length;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        if self.size_for_compaction > MAX_COMPACTION_SIZE {
-            let now = SystemTime::now();
-            info!("Compaction starts");
-            self.compact()?;
-            info!("Compaction finished, costed {:?}", now.elapsed());
+    //removes up to max of the keys expired by now, the earliest first; returns whether more are due
+    //no tombstone is needed: replaying the log drops a record that has expired as well
+    //under the writer lock, so no write of a key can come between the check and the removal
+    fn remove_expired(&mut self, max: usize) -> bool {
+        let now = command::now_millis();
+        for _ in 0..max {
+            let (expires_at, key) = match self.expiries.first() {
+                Some((expires_at, _)) if *expires_at <= now => self.expiries.pop_first().unwrap(),
+                _ => return false,
+            };
+            //the compactor may have moved the record meanwhile, but it keeps the expiry
+            if let Some((_, position)) = self.index.remove_if(&key, |_, current| current.expires_at == Some(expires_at)) {
+                self.invalidate_cached(&key);
+                self.size_for_compaction += position.length;
+            }
         }
+        true
+    }
 
-        Ok(())
+    //the cached value would miss anyway as its CommandPos is outdated, this frees the memory
+    fn invalidate_cached(&self, key: &[u8]) {
+        if let Some(cache) = &self.cache {
+            cache.remove(key);
+        }
     }
-   
-    fn remove(&mut self, key: String) -> Result<()> {
-    //hashmap get() returns an Option
-    if self.index.get(&key).is_some() {
-        //update the index
-        let setcod_len_tobe_destoryed = self.index.remove(&key).
-            map(|(_,p)|p.length).unwrap_or(0);
-        self.size_for_compaction += setcod_len_tobe_destoryed;
-        
-        //initialize the command Rm()
-        let command = Command::RM(key);
-        //get the current writer offset
-        let offset0 = self.current_writer.get_position();
-        //serialize the command
-        let serialized_command = serde_json::to_vec(&command)?;
-        //update the writer
-        self.current_writer.write_all(&serialized_command)?;
+
+    fn flush(&mut self) -> Result<()> {
         self.current_writer.flush()?;
-        //pattern matching get the key
-        
-        self.size_for_compaction += self.current_writer.get_position() - offset0;
-        
-        if self.size_for_compaction > MAX_COMPACTION_SIZE {
-            let now = SystemTime::now();
-            info!("Compaction starts");
-            self.compact()?;
-            info!("Compaction finished, costed {:?}", now.elapsed());
-        }
+        //BufWriter::flush only reaches the page cache, fsync the active file as well
+        //the sealed ones were synced by create_new_file
+        self.current_writer.bufwriter.get_ref().sync_all()?;
+        self.unsynced = false;
+        Ok(())
+    }
 
+    //compact when enough of the logs is stale, otherwise roll the active file once it is full
+    fn after_write(&mut self) -> Result<()> {
+        if self.size_for_compaction > self.options.compaction_threshold
+            && self.size_for_compaction as f64 >= self.total_size as f64 * self.options.compaction_ratio
+            && !self.compacting.load(Ordering::SeqCst)
+        {
+            self.schedule_compaction()?;
+        } else if self.current_writer.get_position() >= self.options.max_file_size {
+            self.create_new_file(self.current_file_id + 1)?;
+        }
         Ok(())
-        } else {
-            Err(KVStoreError::KeyNotFound)
+    }
+
+    //apply the sync policy to the records just written
+    fn sync_after_write(&mut self) -> io::Result<()> {
+        match self.options.sync_policy {
+            SyncPolicy::Never => {}
+            SyncPolicy::EveryWrite => self.current_writer.sync_data()?,
+            SyncPolicy::Interval(_) => self.unsynced = true,
         }
+        Ok(())
     }
 
-    fn compact(& mut self) -> Result<()> {
-        self.create_new_file()?;
-        //traverse the hashmap 
-        let mut before_offset = 0;
-        for mut entry in self.index.iter_mut() {
-            //get the index entry into reader
-            let position = entry.value_mut();
-            self.current_readers.read_add(position, |mut databuf| {
-                io::copy(&mut databuf, &mut self.current_writer)?;
-                Ok(())
-            })?;
+    //seal the active file and hand every file before it to the compactor
+    //the compacted file gets the id right after the sealed ones, new writes go behind it,
+    //so replaying the files in id order still sees the newest record of a key last
+    fn schedule_compaction(&mut self) -> Result<()> {
+        let compaction_file_id = self.current_file_id + 1;
+        self.create_new_file(compaction_file_id + 1)?;
 
-            let offset1_in_writer = self.current_writer.position;
-            //update the index: key -> value, as value pos has been changed
-            *position = CommandPos {
-                //offset : offset0_in_writer,
-                offset : before_offset,
-                length : offset1_in_writer - before_offset,
-                file_id : self.current_file_id,
-            };  
-            before_offset = offset1_in_writer; 
+        self.compacting.store(true, Ordering::SeqCst);
+        if let Some(sender) = &self.compaction_sender {
+            if sender.send(compaction_file_id).is_err() {
+                self.compacting.store(false, Ordering::SeqCst);
+                error!("compactor is gone, compaction into data_{}.txt skipped", compaction_file_id);
+                return Ok(());
+            }
         }
-        self.current_writer.flush()?;
-                
-        self.current_readers.compaction_number.store(self.current_file_id, Ordering::SeqCst);
-        self.current_readers.remove_useless_reader_in_writer(self.current_file_id)?;
+        //the stale bytes are all in the sealed files, they are gone once the compactor is done
+        self.total_size -= self.size_for_compaction;
         self.size_for_compaction = 0;
-        self.create_new_file()?; 
         Ok(())
     }
 
-    fn create_new_file(& mut self) -> Result<()> {
-
-        self.current_file_id += 1;
+    //switch to data_{file_id}.txt, which becomes live once the manifest lists it
+    fn create_new_file(& mut self, file_id: u64) -> Result<()> {
         //dir_path is the current execution path to be joined to create the absolute path
-        //build the new file path based on dir_path and current file id
-        let new_file_path = self.dir_path.join(format!("data_{}.txt", self.current_file_id));
+        //build the new file path based on dir_path and the new file id
+        let new_file_path = self.dir_path.join(format!("data_{}.txt", file_id));
 
         //OpenOptions for opening the new file
+        //a file with this name is not live, e.g. left by a crash before the manifest update, so start empty
         let new_file = OpenOptions::new()
-        .create(true) //(2) create_new(true)当存在就失败
-        //.write(true)
-        .append(true) //if the file exists, then append data to the file
+        .create(true)
+        .write(true)
+        .truncate(true)
         .open(&new_file_path)?;
 
+        //the sealed file may still sit partly in the buffer or the page cache: later syncs only reach
+        //the new file, and open only tolerates a torn tail in the newest one, so sync it under every policy,
+        //which costs one fsync per max_file_size
+        self.current_writer.sync_data()?;
+        self.unsynced = false;
+        manifest::update(&self.manifest, &self.dir_path, |manifest| manifest.files.push(file_id))?;
         //update the current_writer with the newest file handle
         self.current_writer = BufWriterWithPos::new(new_file)?;
+        self.current_file_id = file_id;
+        self.active_file_id.store(file_id, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+//wait for a running compaction, so the files are consistent once the last KvStore is dropped
+impl Drop for Writer {
+    fn drop(&mut self) {
+        //closing the channel stops the compactor loop
+        drop(self.compaction_sender.take());
+        if let Some(compactor) = self.compactor.take() {
+            if compactor.join().is_err() {
+                error!("compactor thread panicked");
+            }
+        }
+    }
+}
+
+impl Compactor {
+    fn spawn(self, receiver: mpsc::Receiver<u64>) -> Result<JoinHandle<()>> {
+        let handle = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || {
+                for compaction_file_id in receiver {
+                    let now = SystemTime::now();
+                    info!("Compaction starts");
+                    match self.compact(compaction_file_id) {
+                        Ok(()) => info!("Compaction finished, costed {:?}", now.elapsed()),
+                        Err(e) => error!("Compaction into data_{}.txt failed: {}", compaction_file_id, e),
+                    }
+                    self.compacting.store(false, Ordering::SeqCst);
+                }
+            })?;
+        Ok(handle)
+    }
+
+    //crash safe: the merged data goes to data_{id}.txt.tmp, which only replaces the sealed files
+    //after it is synced and recorded in the manifest, see recover_compaction
+    fn compact(&self, compaction_file_id: u64) -> Result<()> {
+        let compaction_path = self.dir_path.join(format!("data_{}.txt", compaction_file_id));
+        let tmp_path = self.dir_path.join(format!("data_{}.txt.tmp", compaction_file_id));
+        let mut compaction_writer = BufWriterWithPos::new(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+        )?;
+
+        //the sealed files never change, only the index entries pointing into them are copied
+        //new writes go to files after compaction_file_id and are not touched
+        //expired keys are not copied, the sweeper drops their index entries without reading them
+        let now = command::now_millis();
+        let live_entries: Vec<(Vec<u8>, CommandPos)> = self.index
+            .iter()
+            .filter(|entry| entry.value().file_id < compaction_file_id && !entry.value().is_expired(now))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+
+        let mut new_positions = Vec::with_capacity(live_entries.len());
+        for (key, old_position) in live_entries {
+            let new_position = self.readers.copy_record(&old_position, &mut compaction_writer, compaction_file_id)?;
+            new_positions.push((key, old_position, new_position));
+        }
+        //readers must not see the new positions before the data is on disk
+        compaction_writer.sync_data()?;
+        let data_len = compaction_writer.get_position();
+        drop(compaction_writer);
+
+        //commit point: after this the compaction is finished by recover_compaction even if we crash
+        manifest::update(&self.manifest, &self.dir_path, |manifest| manifest.commit_compaction(compaction_file_id))?;
+        fs::rename(&tmp_path, &compaction_path)?;
+        manifest::sync_dir(&self.dir_path)?;
 
-        //update the current_reader by inserting <the newest file_id, Bufreader> 
-        self.current_readers.readers.borrow_mut().insert(self.current_file_id, BufReader::new(File::open(&new_file_path)?));
+        //without a hint the next open just replays the log, so a failure is not fatal
+        let hint_entries = new_positions.iter()
+            .map(|(key, _, position)| (&key[..], position.offset, position.length, position.expires_at));
+        if let Err(e) = hint::write(&self.dir_path, compaction_file_id, data_len, hint_entries) {
+            warn!("can not write the hint of {:?}: {}", compaction_path, e);
+        }
+
+        //a key set or removed during the copy has a newer entry already, keep that one
+        for (key, old_position, new_position) in new_positions {
+            if let Some(mut position) = self.index.get_mut(&key) {
+                if *position == old_position {
+                    *position = new_position;
+                    if let Some(cache) = &self.cache {
+                        cache.remove(&key);
+                    }
+                }
+            }
+        }
 
+        //no index entry points into the sealed files anymore
+        self.readers.remove_stale_files(compaction_file_id)?;
+        manifest::sync_dir(&self.dir_path)?;
+        manifest::update(&self.manifest, &self.dir_path, |manifest| manifest.pending_compaction = None)?;
         Ok(())
     }
 }
 
 impl Reader {
-    fn read_command(&self, postion: &CommandPos) -> Result<Option<String>> {
-        self.read_add(postion, |data_reader| {
-            if let Command::SET(_, value) = serde_json::from_reader(data_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KVStoreError::UnknownCommandType)
+    fn read_command(&self, postion: &CommandPos) -> Result<Option<Vec<u8>>> {
+        self.with_record(postion, |record| {
+            match Command::decode(record)? {
+                Command::SET(_, value) | Command::SET_TTL(_, value, _) => Ok(Some(value)),
+                Command::RM(_) => Err(KVStoreError::UnknownCommandType),
             }
         })
     }
 
-    fn read_add<F,R>(&self, postion: &CommandPos, f: F) -> Result<R> 
+    //append the record at postion to writer, returning where it is in file_id now
+    fn copy_record(&self, postion: &CommandPos, writer: &mut BufWriterWithPos<File>, file_id: u64) -> Result<CommandPos> {
+        let offset = writer.get_position();
+        self.with_record(postion, |record| {
+            Ok(writer.write_all(&command::without_batch_flag(record))?)
+        })?;
+        Ok(CommandPos {
+            offset,
+            length: writer.get_position() - offset,
+            file_id,
+            seq: postion.seq,
+            expires_at: postion.expires_at,
+        })
+    }
+
+    //hand the raw bytes of the record at postion to f
+    //a mapped file is sliced directly, otherwise the record is read into a buffer
+    fn with_record<F, R>(&self, postion: &CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(Take<&mut BufReader<File>>) -> Result<R>
+        F: FnOnce(&[u8]) -> Result<R>
     {
-        self.try_to_remove_stale_readers_in_reader();
-
-        let mut readers = self.readers.borrow_mut();
-        //check if reader exists, if not, open it
-        if let Entry::Vacant(entry) = readers.entry(postion.file_id) {
-            let new_reader = BufReader::new(File::open(
-                &self
-                        .dir_path
-                        .join(format!("data_{}.txt", postion.file_id)),
-            )?);
-            entry.insert(new_reader);
-        }
-        //locates the Bufreader
-        let source_reader = readers.get_mut(&postion.file_id).expect("can not find key in files in opened readers during locating commandpos");
-        //locates the commandpos start position
-        source_reader.seek(SeekFrom::Start(postion.offset))?;
-        //get the readerbuf of this command by taken to its length
-        let data_reader = source_reader.take(postion.length as u64);
-        //handle this command reader buffer
-        f(data_reader)
-    }
-
-    //删除小于file_id的所有文件在writer中
-    fn remove_useless_reader_in_writer(&mut self, file_id: u64) -> Result<()> {
-        let mut readers = self.readers.borrow_mut();
-        
-        let deleted_file_ids: Vec<u64> = readers
-            .iter()
-            .map(|(key,_)|*key)
-            .filter(|key|*key < file_id)
+        match &*self.file(postion.file_id)? {
+            LogFile::Mmap(mmap) => {
+                let record = usize::try_from(postion.offset).ok()
+                    .and_then(|start| mmap.get(start..start + postion.length as usize))
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                f(record)
+            }
+            LogFile::File(file) => {
+                let mut record = vec![0; postion.length as usize];
+                read_exact_at(file, &mut record, postion.offset)?;
+                f(&record)
+            }
+        }
+    }
+
+    //the shared handle of a log file, opened if no clone has opened it yet
+    fn file(&self, file_id: u64) -> Result<Arc<LogFile>> {
+        if let Some(file) = self.files.get(&file_id) {
+            return Ok(Arc::clone(file.value()));
+        }
+        //keep at most cache_size files open, closing another one to make room
+        //a read still holding the evicted handle keeps it alive until it is done
+        if self.files.len() >= self.cache_size {
+            if let Some(evicted) = self.files.iter().map(|entry| *entry.key()).min() {
+                self.files.remove(&evicted);
+            }
+        }
+        let file = File::open(self.dir_path.join(format!("data_{}.txt", file_id)))?;
+        let file = if self.mmap_sealed_files && file_id < self.active_file_id.load(Ordering::SeqCst) {
+            //safety: sealed files are never written or truncated again, only deleted
+            LogFile::Mmap(unsafe { Mmap::map(&file)? })
+        } else {
+            LogFile::File(file)
+        };
+        Ok(Arc::clone(self.files.entry(file_id).or_insert(Arc::new(file)).value()))
+    }
+
+    //删除小于file_id的所有文件
+    //files a snapshot still reads from are only deleted once it is dropped, see unpin
+    fn remove_stale_files(&self, file_id: u64) -> Result<()> {
+        self.files.retain(|id, _| *id >= file_id);
+        let mut pins = self.pins.lock().unwrap();
+
+        let deleted_file_ids: Vec<u64> = KvStore::log_files_on_disk(&self.dir_path)?
+            .into_iter()
+            .filter(|id|*id < file_id)
             .collect();
         
         for number in deleted_file_ids {
-            
-            //remove the readers <HashMap<file_id, bufread>> maintained in Writers 
-            readers.remove(&number);
-            //delete those files older than compaction_number
+            if pins.counts.contains_key(&number) {
+                pins.stale.insert(number);
+                hint::remove(&self.dir_path, number)?;
+                continue;
+            }
+            //delete those files older than file_id
             let file_path = self.dir_path.join(format!("data_{}.txt", number));
+            //a sealed file left behind could resurrect keys whose tombstone is gone,
+            //so fail and leave the manifest pending for recover_compaction to retry
             if let Err(e) = remove_file(&file_path) {
-                warn!("can not delete file {:?} because {}", file_path, e)
+                warn!("can not delete file {:?} because {}", file_path, e);
+                return Err(e.into());
             }
+            hint::remove(&self.dir_path, number)?;
         }
         Ok(())
     }
 
-    fn try_to_remove_stale_readers_in_reader(&self) {
-        let compaction_number = self.compaction_number.load(Ordering::SeqCst);
-        let mut readers = self.readers.borrow_mut();
-        //delete the readers older than compaction_number
-        readers.retain(|&k, _|k >= compaction_number);
+    //release the files of a dropped snapshot, deleting those no live snapshot and no compaction needs
+    fn unpin(&self, file_ids: &[u64]) {
+        let mut pins = self.pins.lock().unwrap();
+        for id in file_ids {
+            match pins.counts.get_mut(id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    continue;
+                }
+                _ => pins.counts.remove(id),
+            };
+            if pins.stale.remove(id) {
+                self.files.remove(id);
+                let file_path = self.dir_path.join(format!("data_{}.txt", id));
+                //the next open removes it, as it comes before every live file
+                if let Err(e) = remove_file(&file_path) {
+                    warn!("can not delete file {:?} because {}", file_path, e);
+                }
+            }
+        }
+    }
+}
+
+//pread: reads at offset without touching the cursor of the shared handle
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
-}
\ No newline at end of file
+    Ok(())
+}
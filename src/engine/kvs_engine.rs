@@ -1,8 +1,95 @@
-use crate::Result; //type in error.rs
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::{KVStoreError, Result}; //type in error.rs
+use crate::{Snapshot, Transaction, WriteBatch};
 
 pub trait KvsEngine: Clone + Send + 'static {
-  fn set(& self, key: String, value: String) -> Result<()>;
-  fn get(& self, key: String) -> Result<Option<String>>;
-  fn remove(& self, key: String) -> Result<()>;
+  type Snapshot: Snapshot;
+
+  //keys and values are raw bytes, the String methods below wrap these
+  fn set_bytes(& self, key: &[u8], value: &[u8]) -> Result<()>;
+  fn get_bytes(& self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+  fn remove_bytes(& self, key: &[u8]) -> Result<()>;
+  //pairs with start <= key < end (no upper bound if end is None) in byte order, at most limit of them
+  fn scan_bytes(& self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+  //every pair whose key starts with prefix, in byte order
+  fn scan_prefix_bytes(& self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+  fn set(& self, key: String, value: String) -> Result<()> {
+    self.set_bytes(key.as_bytes(), value.as_bytes())
+  }
+  //fails with Utf8Error if the value is not UTF-8, get_bytes reads it anyway
+  fn get(& self, key: String) -> Result<Option<String>> {
+    Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+  }
+  fn remove(& self, key: String) -> Result<()> {
+    self.remove_bytes(key.as_bytes())
+  }
+  fn scan(& self, start: String, end: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+    into_string_pairs(self.scan_bytes(start.as_bytes(), end.as_ref().map(String::as_bytes), limit)?)
+  }
+  fn scan_prefix(& self, prefix: String) -> Result<Vec<(String, String)>> {
+    into_string_pairs(self.scan_prefix_bytes(prefix.as_bytes())?)
+  }
+
+  //like set_bytes, but once ttl has passed the key is gone as if removed
+  //any later write of the key replaces the ttl, a plain set keeps it forever
+  fn set_with_ttl_bytes(& self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()>;
+  fn set_with_ttl(& self, key: String, value: String, ttl: Duration) -> Result<()> {
+    self.set_with_ttl_bytes(key.as_bytes(), value.as_bytes(), ttl)
+  }
+  //apply every set and remove of the batch, all or nothing if the process crashes meanwhile
+  fn write_batch(& self, batch: WriteBatch) -> Result<()>;
+  //run f on a consistent view of the store and apply its writes atomically,
+  //failing with TransactionConflict if a key f read was written by someone else before the commit
+  //f may run more than once, see retry_on_conflict
+  fn transaction<F, R>(& self, f: F) -> Result<R>
+  where
+    F: Fn(&mut dyn Transaction) -> Result<R>;
+  //atomically: if the value of key is expected (None: the key is missing), replace it with new
+  //(None: remove the key) and return true, otherwise leave it alone and return false
+  fn compare_and_swap_bytes(& self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool>;
+  fn compare_and_swap(& self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+    self.compare_and_swap_bytes(key.as_bytes(), expected.as_ref().map(String::as_bytes), new.as_ref().map(String::as_bytes))
+  }
+  fn set_if_absent(& self, key: String, value: String) -> Result<bool> {
+    self.compare_and_swap(key, None, Some(value))
+  }
+  fn set_if_equals(& self, key: String, expected: String, value: String) -> Result<bool> {
+    self.compare_and_swap(key, Some(expected), Some(value))
+  }
+  fn remove_if_equals(& self, key: String, expected: String) -> Result<bool> {
+    self.compare_and_swap(key, Some(expected), None)
+  }
+  //atomically add delta to the integer stored at key (a missing key counts as 0) and return the sum
+  //fails with NotAnInteger if the value is not a decimal i64, leaving it unchanged
+  fn incr_bytes(& self, key: &[u8], delta: i64) -> Result<i64>;
+  fn incr(& self, key: String, delta: i64) -> Result<i64> {
+    self.incr_bytes(key.as_bytes(), delta)
+  }
+  //a read-only view of the store as it is now, writes made after this returns do not show in it
+  //not free, the costs of taking and holding one are on each engine's snapshot
+  fn snapshot(& self) -> Result<Self::Snapshot>;
+  //write a consistent copy of the store into backup_path, which must be empty or missing,
+  //while the store keeps serving; each engine has a restore constructor opening a copy of it
+  fn backup_to(& self, backup_path: impl Into<PathBuf>) -> Result<()>;
+  //push everything written so far down to the disk, called before the server exits
+  fn flush(& self) -> Result<()>;
 }
 
+fn into_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+  pairs.into_iter()
+    .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+    .collect()
+}
+
+//the value incr stores at key in place of value
+pub(crate) fn incremented(key: &[u8], value: Option<&[u8]>, delta: i64) -> Result<i64> {
+  let current = match value {
+    Some(value) => std::str::from_utf8(value).ok()
+      .and_then(|value| value.parse::<i64>().ok())
+      .ok_or_else(|| KVStoreError::NotAnInteger(String::from_utf8_lossy(key).into_owned()))?,
+    None => 0,
+  };
+  current.checked_add(delta).ok_or_else(|| KVStoreError::IntegerOverflow(String::from_utf8_lossy(key).into_owned()))
+}
@@ -0,0 +1,69 @@
+// Log files of the first store format: serde_json encoded commands written back to back,
+// e.g. {"SET":["key","value"]}{"RM":"key"}, without checksums, lengths or a MANIFEST.
+// KvStore::open converts them into binary records before anything else reads them.
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use log::{error, info};
+use serde::Deserialize;
+use crate::{Command, Result};
+use super::manifest;
+
+#[derive(Deserialize)]
+enum LegacyCommand {
+    #[serde(rename = "SET")]
+    Set(String, String),
+    #[serde(rename = "RM")]
+    Rm(String),
+}
+
+// a binary record starts with its crc, which may happen to be a '{' as well,
+// so a log only counts as legacy when it starts like a JSON command and is no valid binary record
+pub(crate) fn is_legacy_log(file_path: &Path) -> Result<bool> {
+    let mut start = Vec::new();
+    File::open(file_path)?.take(7).read_to_end(&mut start)?;
+    if !start.starts_with(b"{\"SET\":") && !start.starts_with(b"{\"RM\":") {
+        return Ok(false);
+    }
+    let mut reader = BufReader::new(File::open(file_path)?);
+    Ok(Command::read_from(&mut reader).is_err())
+}
+
+// rewrite a legacy log as binary records in place, through a temp file so a crash leaves
+// either the untouched legacy log or the complete converted one
+// a legacy log that does not parse to its end is refused, it is never truncated
+pub(crate) fn convert(dir_path: &Path, file_id: u64) -> Result<()> {
+    let file_path = dir_path.join(format!("data_{}.txt", file_id));
+    //an interrupted conversion leaves data_{id}.txt.tmp, which the next open removes
+    let tmp_path = dir_path.join(format!("data_{}.txt.tmp", file_id));
+    let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+    let count = match write_records(&file_path, &mut writer) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("can not convert the legacy JSON log {:?}, it is left as it is: {}", file_path, e);
+            drop(writer);
+            fs::remove_file(&tmp_path)?;
+            return Err(e);
+        }
+    };
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, &file_path)?;
+    manifest::sync_dir(dir_path)?;
+    info!("converted {} commands of the legacy JSON log {:?} to binary records", count, file_path);
+    Ok(())
+}
+
+fn write_records(file_path: &Path, writer: &mut impl Write) -> Result<u64> {
+    let mut count = 0;
+    let commands = serde_json::Deserializer::from_reader(BufReader::new(File::open(file_path)?))
+        .into_iter::<LegacyCommand>();
+    for command in commands {
+        let command = match command? {
+            LegacyCommand::Set(key, value) => Command::SET(key.into_bytes(), value.into_bytes()),
+            LegacyCommand::Rm(key) => Command::RM(key.into_bytes()),
+        };
+        writer.write_all(&command.encode_in_batch(false))?;
+        count += 1;
+    }
+    Ok(count)
+}
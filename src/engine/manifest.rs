@@ -0,0 +1,84 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use crate::{KVStoreError, Result};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+// bumped whenever the layout of the directory or of the log records changes
+// 2: records and hints carry the expiry of keys set with a ttl
+pub(crate) const FORMAT_VERSION: u32 = 2;
+
+// Durable state of the KvStore directory besides the log files themselves:
+// the source of truth for which data_{id}.txt are live, files not listed here are ignored
+// always replaced as a whole: written to a temp file, synced, then renamed over the old one
+// a MANIFEST written before it listed the files has none of the other fields, format_version 0
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub(crate) struct Manifest {
+    pub(crate) format_version: u32,
+    // number of finished compactions
+    pub(crate) generation: u64,
+    // ids of the live log files in replay order, the last one is the active file
+    pub(crate) files: Vec<u64>,
+    // set once a compaction has completely written and synced data_{id}.txt.tmp:
+    // from then on that file replaces every log file with a smaller id
+    pub(crate) pending_compaction: Option<u64>,
+}
+
+impl Manifest {
+    // a directory without MANIFEST gets the default one, format_version 0
+    pub(crate) fn load(dir_path: &Path) -> Result<Manifest> {
+        let manifest: Manifest = match fs::read(dir_path.join(MANIFEST_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Manifest::default()),
+            Err(e) => return Err(e.into()),
+        };
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(KVStoreError::UnsupportedFormatVersion(manifest.format_version));
+        }
+        Ok(manifest)
+    }
+
+    pub(crate) fn store(&self, dir_path: &Path) -> Result<()> {
+        let tmp_path = dir_path.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, dir_path.join(MANIFEST_FILE))?;
+        sync_dir(dir_path)
+    }
+
+    // the compacted file takes the place of every file before it
+    pub(crate) fn commit_compaction(&mut self, compaction_file_id: u64) {
+        self.files.retain(|id| *id > compaction_file_id);
+        self.files.insert(0, compaction_file_id);
+        self.generation += 1;
+        self.pending_compaction = Some(compaction_file_id);
+    }
+}
+
+// apply f to a copy of the shared manifest and only keep it once it is on disk,
+// so the one in memory never runs ahead of the durable one
+pub(crate) fn update<F>(manifest: &Mutex<Manifest>, dir_path: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&mut Manifest),
+{
+    let mut manifest = manifest.lock().unwrap();
+    let mut updated = manifest.clone();
+    f(&mut updated);
+    updated.store(dir_path)?;
+    *manifest = updated;
+    Ok(())
+}
+
+// make renames, creations and deletions in the directory durable
+pub(crate) fn sync_dir(dir_path: &Path) -> Result<()> {
+    File::open(dir_path)?.sync_all()?;
+    Ok(())
+}
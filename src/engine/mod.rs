@@ -1,9 +1,26 @@
 mod kvs_engine;
-mod command;
+pub(crate) mod command;
 mod kv;
 mod sled;
+mod options;
+mod manifest;
+mod hint;
+mod cache;
+mod batch;
+mod group_commit;
+mod transaction;
+mod snapshot;
+mod backup;
+mod legacy;
 
 pub use self::kvs_engine::KvsEngine;
 pub use self::command::Command;
 pub use self::kv::KvStore;
 pub use self::sled::SledKvStore;
+pub use self::options::{KvStoreOptions, SyncPolicy};
+pub use self::cache::CacheStats;
+pub use self::batch::WriteBatch;
+pub use self::transaction::{retry_on_conflict, Transaction};
+pub use self::snapshot::{ExpiringSnapshotIter, Snapshot, SnapshotIter};
+pub use self::kv::KvSnapshot;
+pub use self::sled::SledSnapshot;
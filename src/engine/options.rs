@@ -0,0 +1,144 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::{KVStoreError, Result};
+
+// When written data is forced from the OS page cache to the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // never sync explicitly, the OS writes the pages back whenever it likes
+    Never,
+    // fsync/fdatasync before every set or remove returns
+    EveryWrite,
+    // a background flusher syncs the written data once per interval
+    Interval(Duration),
+}
+
+// parses "never", "every-write" or "interval:<milliseconds>", as accepted by kvs-server --sync
+impl FromStr for SyncPolicy {
+    type Err = KVStoreError;
+
+    fn from_str(s: &str) -> Result<SyncPolicy> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "every-write" => Ok(SyncPolicy::EveryWrite),
+            _ => s
+                .strip_prefix("interval:")
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .filter(|ms| *ms > 0)
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| KVStoreError::InvalidOption(format!("unknown sync policy {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::EveryWrite => write!(f, "every-write"),
+            SyncPolicy::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+        }
+    }
+}
+
+// Options used by KvStore::open_with and SledKvStore::open_with
+// SledKvStore only looks at the sync policy, the rest tunes the KvStore log files
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: f64,
+    pub(crate) max_file_size: u64,
+    pub(crate) reader_cache_size: usize,
+    pub(crate) mmap_sealed_files: bool,
+    pub(crate) value_cache_size: usize,
+    pub(crate) expiry_sweep_interval: Duration,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::Never,
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: 0.5,
+            max_file_size: 64 * 1024 * 1024,
+            reader_cache_size: 16,
+            mmap_sealed_files: false,
+            value_cache_size: 0,
+            expiry_sweep_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    // compaction runs once more than this many bytes of the logs are stale ...
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    // ... and the stale bytes are at least this fraction (0.0 to 1.0) of all log bytes
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    // the active data_{id}.txt is sealed and a new one started once it reaches this size
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    // max number of log files the readers keep open
+    pub fn reader_cache_size(mut self, files: usize) -> Self {
+        self.reader_cache_size = files;
+        self
+    }
+
+    // read the sealed log files through a memory map instead of pread
+    // the active file keeps using pread, as it still grows
+    pub fn mmap_sealed_files(mut self, enabled: bool) -> Self {
+        self.mmap_sealed_files = enabled;
+        self
+    }
+
+    // bytes of keys and values KvStore::get keeps in an LRU cache, 0 turns the cache off
+    pub fn value_cache_size(mut self, bytes: usize) -> Self {
+        self.value_cache_size = bytes;
+        self
+    }
+
+    // how often a background thread removes keys whose ttl has passed
+    // get hides them right away, the sweep frees their space
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiry_sweep_interval = interval;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.compaction_ratio) {
+            return Err(KVStoreError::InvalidOption(format!(
+                "compaction ratio {} is not between 0 and 1", self.compaction_ratio)));
+        }
+        if self.max_file_size == 0 {
+            return Err(KVStoreError::InvalidOption("max file size must be positive".to_owned()));
+        }
+        if self.reader_cache_size == 0 {
+            return Err(KVStoreError::InvalidOption("reader cache size must be positive".to_owned()));
+        }
+        if self.expiry_sweep_interval.is_zero() {
+            return Err(KVStoreError::InvalidOption("expiry sweep interval must be positive".to_owned()));
+        }
+        Ok(())
+    }
+}
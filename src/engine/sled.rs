@@ -1,43 +1,627 @@
-
-use std::path::PathBuf;
-use crate::{KvsEngine,KVStoreError,Result};
+use std::fs::{File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::error;
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionResult, TransactionalTree,
+    UnabortableTransactionError,
+};
+use sled::{IVec, Transactional};
+use crate::{Command,KvsEngine,KVStoreError,KvStoreOptions,Result,ExpiringSnapshotIter,Snapshot,SyncPolicy,Transaction,WriteBatch};
+use super::command;
+use super::backup;
+use super::kvs_engine::incremented;
 
 #[derive(Clone)]
 pub struct SledKvStore {
     inner: sled::Db,
+    //expiry of the keys set with a ttl, as unix time in milliseconds
+    //every other write of a key removes it from here
+    expiry: sled::Tree,
+    //flush the db before each write returns
+    sync_every_write: bool,
+    //writes hold it shared, snapshot() exclusively, so a snapshot never sees a write halfway
+    write_lock: Arc<RwLock<()>>,
+    //every write saves the old state of its keys here for the snapshots still open
+    snapshots: Arc<OpenSnapshots>,
+    //stopped once the last clone is dropped
+    _sweeper: Arc<Sweeper>,
 }
 
 impl SledKvStore {
+    //flushes on every write, as SledKvStore always did
     pub fn open (open_path: impl Into<PathBuf>) -> Result<SledKvStore> {
-        let inner_sleddb = sled::open(open_path.into())?;
+        SledKvStore::open_with(open_path, KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite))
+    }
+
+    pub fn open_with(open_path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<SledKvStore> {
+        options.validate()?;
+        //sled has its own background flusher, use it for the interval policy
+        let flush_every_ms = match options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(interval.as_millis() as u64),
+            SyncPolicy::Never | SyncPolicy::EveryWrite => None,
+        };
+        let open_path = open_path.into();
+        wait_for_lock(&open_path)?;
+        let inner_sleddb = sled::Config::new()
+            .path(open_path)
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        let expiry = inner_sleddb.open_tree("expiry")?;
+        let write_lock = Arc::new(RwLock::new(()));
+        let snapshots = Arc::new(OpenSnapshots::default());
+        let sweeper = Sweeper::spawn(
+            inner_sleddb.clone(), expiry.clone(), Arc::clone(&write_lock), Arc::clone(&snapshots), options.expiry_sweep_interval,
+        )?;
         
         Ok(SledKvStore {
             inner: inner_sleddb,
+            expiry,
+            sync_every_write: options.sync_policy == SyncPolicy::EveryWrite,
+            write_lock,
+            snapshots,
+            _sweeper: Arc::new(sweeper),
         })
     }
+
+    //open a copy of a backup written by backup_to at open_path, which must be empty
+    pub fn restore(backup_path: impl Into<PathBuf>, open_path: impl Into<PathBuf>) -> Result<SledKvStore> {
+        let backup_path = backup_path.into();
+        let open_path = open_path.into();
+        //or sled would create an empty db in its place
+        if !backup_path.join("conf").is_file() {
+            return Err(KVStoreError::InvalidBackup(backup_path.display().to_string()));
+        }
+        backup::create_empty_dir(&open_path)?;
+        wait_for_lock(&backup_path)?;
+        let backup = sled::open(backup_path)?;
+        copy_db(&backup, &open_path)?;
+        drop(backup);
+        SledKvStore::open(open_path)
+    }
+
+    fn sync_after_write(&self) -> Result<()> {
+        if self.sync_every_write {
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+
+    //write the value of key and its expiry in one transaction, None as value removes the key
+    //returns whether the key was there and not expired before
+    fn write_key(&self, key: &[u8], value: Option<&[u8]>, expires_at: Option<u64>) -> Result<bool> {
+        let _writing = self.write_lock.read().unwrap();
+        let now = command::now_millis();
+        let result: TransactionResult<bool, KVStoreError> = (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            let old_expiry = match expires_at {
+                Some(expires_at) => expiry.insert(key, &expires_at.to_le_bytes()[..])?,
+                None => expiry.remove(key)?,
+            };
+            let old_value = match value {
+                Some(value) => data.insert(key, value)?,
+                None => data.remove(key)?,
+            };
+            self.snapshots.save(key, old_value.clone(), old_expiry.clone());
+            Ok(old_value.is_some() && !old_expiry.is_some_and(|old_expiry| is_expired(&old_expiry, now)))
+        });
+        let existed = result.map_err(from_transaction_error)?;
+        self.sync_after_write()?;
+        Ok(existed)
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(self.expiry.get(key)?.is_some_and(|expires_at| is_expired(&expires_at, now)))
+    }
+
+    //the pairs of iter whose key has not expired, at most limit of them
+    fn live_pairs(&self, iter: sled::Iter, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = command::now_millis();
+        let mut pairs = Vec::new();
+        for item in iter {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, value) = item?;
+            if !self.is_expired(&key, now)? {
+                pairs.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        Ok(pairs)
+    }
 }
 
 impl KvsEngine for SledKvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.inner.insert(key, value.into_bytes())?; //into_bytes return the vec
-        self.inner.flush()?; 
+    type Snapshot = SledSnapshot;
+
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write_key(key, Some(value), None)?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let val = self.inner.get(key)?.map(|vec| vec.to_vec());
+        if val.is_some() && self.is_expired(key, command::now_millis())? {
+            return Ok(None);
+        }
+        Ok(val) //Vec<u8>和IVec
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        // an expired key is removed as well, but counts as missing
+        if !self.write_key(key, None, None)? {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    fn scan_bytes(&self, start: &[u8], end: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let iter = match end {
+            //sled::Db::range panics on a reversed range
+            Some(end) if end <= start => return Ok(Vec::new()),
+            Some(end) => self.inner.range(start..end),
+            None => self.inner.range(start..),
+        };
+        self.live_pairs(iter, limit)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.live_pairs(self.inner.scan_prefix(prefix), usize::MAX)
+    }
+
+    fn set_with_ttl_bytes(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.write_key(key, Some(value), Some(command::expires_at(ttl)))?;
+        Ok(())
+    }
+
+    //the values and their expiries are written in one transaction, atomic also across a crash
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _writing = self.write_lock.read().unwrap();
+        let result: TransactionResult<(), KVStoreError> = (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            for command in batch.commands() {
+                let old_value = match command {
+                    Command::SET(key, value) | Command::SET_TTL(key, value, _) => data.insert(&key[..], &value[..])?,
+                    Command::RM(key) => data.remove(&key[..])?,
+                };
+                let old_expiry = match command.expires_at() {
+                    Some(expires_at) => expiry.insert(command.key(), &expires_at.to_le_bytes()[..])?,
+                    None => expiry.remove(command.key())?,
+                };
+                self.snapshots.save(command.key(), old_value, old_expiry);
+            }
+            Ok(())
+        });
+        result.map_err(from_transaction_error)?;
+        self.sync_after_write()?;
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(&self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        let _writing = self.write_lock.read().unwrap();
+        let now = command::now_millis();
+        let result: TransactionResult<bool, KVStoreError> = (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            //an expired key compares as missing
+            let current = live_value(data, expiry, key, now)?;
+            if current.as_deref() != expected {
+                return Ok(false);
+            }
+            let old_value = match new {
+                Some(new) => data.insert(key, new)?,
+                None => data.remove(key)?,
+            };
+            let old_expiry = expiry.remove(key)?;
+            self.snapshots.save(key, old_value, old_expiry);
+            Ok(true)
+        });
+        let swapped = result.map_err(from_transaction_error)?;
+        if swapped {
+            self.sync_after_write()?;
+        }
+        Ok(swapped)
+    }
+
+    fn incr_bytes(&self, key: &[u8], delta: i64) -> Result<i64> {
+        let _writing = self.write_lock.read().unwrap();
+        let now = command::now_millis();
+        let result: TransactionResult<i64, KVStoreError> = (&*self.inner, &self.expiry).transaction(|(data, expiry)| {
+            //an expired key counts as 0
+            let current = live_value(data, expiry, key, now)?;
+            let sum = incremented(key, current.as_deref(), delta).map_err(ConflictableTransactionError::Abort)?;
+            let old_value = data.insert(key, sum.to_string().into_bytes())?;
+            let old_expiry = expiry.remove(key)?;
+            self.snapshots.save(key, old_value, old_expiry);
+            Ok(sum)
+        });
+        let sum = result.map_err(from_transaction_error)?;
+        self.sync_after_write()?;
+        Ok(sum)
+    }
+
+    //sled reruns f itself when the transaction conflicts, so TransactionConflict is never returned
+    fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<R>,
+    {
+        let _writing = self.write_lock.read().unwrap();
+        let result = (&*self.inner, &self.expiry).transaction(|(tree, expiry)| {
+            let mut txn = SledTransaction { tree, expiry, snapshots: &self.snapshots, now: command::now_millis(), error: None };
+            match f(&mut txn) {
+                Ok(result) => Ok(result),
+                //a sled conflict must reach sled to be retried, even if f turned it into another error
+                Err(_) if txn.error.is_some() => Err(txn.error.take().unwrap().into()),
+                Err(e) => Err(ConflictableTransactionError::Abort(e)),
+            }
+        });
+        let result = result.map_err(from_transaction_error)?;
+        self.sync_after_write()?;
+        Ok(result)
+    }
+
+    //copied from a snapshot, so writes only wait while it is taken, not for the copy
+    //every write meanwhile saves the old state of its keys for it though, see snapshot
+    fn backup_to(&self, backup_path: impl Into<PathBuf>) -> Result<()> {
+        let backup_path = backup_path.into();
+        backup::create_empty_dir(&backup_path)?;
+        let snapshot = self.snapshot()?;
+        let copy = sled::open(&backup_path)?;
+        let copy_expiry = copy.open_tree("expiry")?;
+        for entry in snapshot.entries(&[], None) {
+            let (key, value, expires_at) = entry?;
+            copy.insert(&key, value)?;
+            if let Some(expires_at) = expires_at {
+                copy_expiry.insert(&key, expires_at)?;
+            }
+        }
+        copy.flush()?;
+        Ok(())
+    }
+
+    //sled has no snapshots of its own, so nothing is copied up front: the snapshot reads the trees,
+    //and every write from then on saves the old value and expiry of its keys for it first
+    //taking one only waits for the writes in flight, holding one costs the memory of the old values
+    //of the keys written meanwhile, and makes each write copy those while it is open
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let _exclusive = self.write_lock.write().unwrap();
+        Ok(SledSnapshot {
+            data: self.inner.clone(),
+            expiry: self.expiry.clone(),
+            taken_at: command::now_millis(),
+            overwritten: self.snapshots.open(),
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+//a SledKvStore snapshot, reading the trees except for the keys written since it was taken
+pub struct SledSnapshot {
+    data: sled::Db,
+    expiry: sled::Tree,
+    //keys that had expired by then are not in the snapshot, later expiries do not remove them
+    taken_at: u64,
+    overwritten: Arc<Overwritten>,
+}
+
+impl SledSnapshot {
+    //the value of a key with its state as of the snapshot, None if it was missing or expired
+    fn visible(&self, value: Option<&IVec>, expires_at: Option<&IVec>) -> Option<IVec> {
+        value.filter(|_| !expires_at.is_some_and(|expires_at| is_expired(expires_at, self.taken_at))).cloned()
+    }
+
+    //the pairs with their expiries, start <= key < end
+    fn entries(&self, start: &[u8], end: Option<&[u8]>) -> SnapshotEntries<'_> {
+        let live = match end {
+            //sled::Db::range panics on a reversed range
+            Some(end) if end <= start => None,
+            Some(end) => Some(self.data.range(start..end)),
+            None => Some(self.data.range(start..)),
+        };
+        SnapshotEntries {
+            snapshot: self,
+            end: end.map(<[u8]>::to_vec),
+            done: Bound::Included(start.to_vec()),
+            found: VecDeque::new(),
+            finished: live.is_none(),
+            live,
+        }
+    }
+}
+
+impl Snapshot for SledSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        //read before looking for a saved state: a write saves it before it commits,
+        //so if there is none yet, the key was not written since the snapshot when it was read
+        let value = self.data.get(key)?;
+        let expires_at = self.expiry.get(key)?;
+        let overwritten = self.overwritten.lock().unwrap();
+        let visible = match overwritten.get(key) {
+            Some((value, expires_at)) => self.visible(value.as_ref(), expires_at.as_ref()),
+            None => self.visible(value.as_ref(), expires_at.as_ref()),
+        };
+        Ok(visible.map(|value| value.to_vec()))
+    }
+
+    fn range_with_expiry(&self, start: &[u8], end: Option<&[u8]>) -> ExpiringSnapshotIter<'_> {
+        Box::new(self.entries(start, end).map(|entry| entry.map(|(key, value, expires_at)| {
+            (key, value.to_vec(), expires_at.as_deref().and_then(expiry_millis))
+        })))
+    }
+}
+
+//the value and expiry keys had before a write replaced them, None for a missing one
+type Overwritten = Mutex<BTreeMap<Vec<u8>, (Option<IVec>, Option<IVec>)>>;
+
+//the snapshots of a store still open
+#[derive(Default)]
+struct OpenSnapshots {
+    snapshots: Mutex<Vec<Weak<Overwritten>>>,
+}
+
+impl OpenSnapshots {
+    fn open(&self) -> Arc<Overwritten> {
+        let overwritten = Arc::new(Mutex::new(BTreeMap::new()));
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|snapshot| snapshot.strong_count() > 0);
+        snapshots.push(Arc::downgrade(&overwritten));
+        overwritten
+    }
+
+    //called by a write transaction before it commits, with what it replaced
+    //a transaction rerun by sled saves again, but each snapshot keeps the first state saved for a key,
+    //which is the one the snapshot saw: no write of the key committed before it was saved
+    fn save(&self, key: &[u8], value: Option<IVec>, expires_at: Option<IVec>) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|snapshot| match snapshot.upgrade() {
+            Some(overwritten) => {
+                overwritten.lock().unwrap().entry(key.to_vec()).or_insert_with(|| (value.clone(), expires_at.clone()));
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+//the pairs of the data tree merged with the saved states of the keys written since the snapshot
+struct SnapshotEntries<'a> {
+    snapshot: &'a SledSnapshot,
+    //None for an empty range
+    live: Option<sled::Iter>,
+    end: Option<Vec<u8>>,
+    //keys up to here are returned or in found
+    done: Bound<Vec<u8>>,
+    found: VecDeque<(Vec<u8>, IVec, Option<IVec>)>,
+    finished: bool,
+}
+
+impl SnapshotEntries<'_> {
+    //the next key of the data tree, and before it the saved keys it no longer holds
+    //a key removed before the iterator got to it was saved before that, so it is in overwritten by now
+    fn find_more(&mut self) -> Result<()> {
+        let next = match self.live.as_mut().and_then(Iterator::next) {
+            Some(item) => {
+                let (key, value) = item?;
+                let expires_at = self.snapshot.expiry.get(&key)?;
+                Some((key.to_vec(), value, expires_at))
+            }
+            None => None,
+        };
+        let upper = match (&next, &self.end) {
+            (Some((key, ..)), _) => Bound::Included(key.clone()),
+            (None, Some(end)) => Bound::Excluded(end.clone()),
+            (None, None) => Bound::Unbounded,
+        };
+        let overwritten = self.snapshot.overwritten.lock().unwrap();
+        for (key, (value, expires_at)) in overwritten.range((self.done.clone(), upper)) {
+            if let Some(value) = self.snapshot.visible(value.as_ref(), expires_at.as_ref()) {
+                self.found.push_back((key.clone(), value, expires_at.clone()));
+            }
+        }
+        match next {
+            Some((key, value, expires_at)) => {
+                if !overwritten.contains_key(&key) {
+                    if let Some(value) = self.snapshot.visible(Some(&value), expires_at.as_ref()) {
+                        self.found.push_back((key.clone(), value, expires_at));
+                    }
+                }
+                self.done = Bound::Excluded(key);
+            }
+            None => self.finished = true,
+        }
         Ok(())
     }
+}
+
+impl Iterator for SnapshotEntries<'_> {
+    type Item = Result<(Vec<u8>, IVec, Option<IVec>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.found.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(e) = self.find_more() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    snapshots: &'a OpenSnapshots,
+    now: u64,
+    //the first error sled reported, the transaction can not go on after it
+    error: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn check<T>(&mut self, result: std::result::Result<T, UnabortableTransactionError>) -> Result<T> {
+        result.map_err(|e| {
+            let err = match &e {
+                UnabortableTransactionError::Conflict => KVStoreError::TransactionConflict,
+                UnabortableTransactionError::Storage(e) => KVStoreError::SledError(e.clone()),
+            };
+            self.error.get_or_insert(e);
+            err
+        })
+    }
+}
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let val = self
-        .inner
-        .get(key)?
-        .map(|vec|vec.to_vec())
-        .map(String::from_utf8)
-        .transpose()?; //utf8 errors !!!
-        Ok(val) //String和IVec
+impl Transaction for SledTransaction<'_> {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.check(live_value(self.tree, self.expiry, key, self.now))?;
+        Ok(value.map(|vec| vec.to_vec()))
     }
-    
-    fn remove(&self, key: String) -> Result<()> {
-        // Db::remove only returns if it existed.
-        self.inner.remove(key)?.ok_or(KVStoreError::KeyNotFound)?;
-        self.inner.flush()?; 
+
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let old_value = self.check(self.tree.insert(key, value))?;
+        let old_expiry = self.check(self.expiry.remove(key))?;
+        self.snapshots.save(key, old_value, old_expiry);
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        let old_value = self.check(self.tree.remove(key))?;
+        let old_expiry = self.check(self.expiry.remove(key))?;
+        self.snapshots.save(key, old_value, old_expiry);
         Ok(())
-    } 
-}
\ No newline at end of file
+    }
+}
+
+//the file sled locks while a db is open
+const SLED_LOCK_FILE: &str = "db";
+
+//sled's threadpool can still write out the last buffer of a db dropped just before, holding its lock
+//meanwhile, so wait for the lock to be free before opening the db; it stays held by another process
+fn wait_for_lock(path: &Path) -> Result<()> {
+    const LOCK_RETRIES: u32 = 50;
+    let file = match File::open(path.join(SLED_LOCK_FILE)) {
+        Ok(file) => file,
+        //a new db
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for _ in 0..LOCK_RETRIES {
+        match file.try_lock() {
+            //released again when file is dropped, before sled takes it
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(20)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+    }
+    Err(KVStoreError::StoreLocked(path.display().to_string()))
+}
+
+//background thread removing expired keys, so they do not stay on disk until read
+struct Sweeper {
+    //never sent on, dropping it stops the thread
+    stop_sender: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    fn spawn(
+        data: sled::Db, expiry: sled::Tree, write_lock: Arc<RwLock<()>>, snapshots: Arc<OpenSnapshots>, interval: Duration,
+    ) -> Result<Sweeper> {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-sweeper".to_owned())
+            .spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    let _writing = write_lock.read().unwrap();
+                    if let Err(e) = sweep_expired(&data, &expiry, &snapshots) {
+                        error!("can not remove expired keys: {}", e);
+                    }
+                }
+            })?;
+        Ok(Sweeper { stop_sender: Some(stop_sender), handle: Some(handle) })
+    }
+}
+
+//the thread holds a handle of the db, so it must be gone before the db can be opened again
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        drop(self.stop_sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("sweeper thread panicked");
+            }
+        }
+    }
+}
+
+fn sweep_expired(data: &sled::Tree, expiry: &sled::Tree, snapshots: &OpenSnapshots) -> Result<()> {
+    let now = command::now_millis();
+    for item in expiry.iter() {
+        let (key, expires_at) = item?;
+        if is_expired(&expires_at, now) {
+            remove_if_expired(data, expiry, snapshots, &key, now)?;
+        }
+    }
+    Ok(())
+}
+
+//checked again inside the transaction, so a key written meanwhile is kept
+//a snapshot taken before the key expired still holds it, so its state is saved like for any write
+fn remove_if_expired(data: &sled::Tree, expiry: &sled::Tree, snapshots: &OpenSnapshots, key: &[u8], now: u64) -> Result<()> {
+    let result: TransactionResult<(), KVStoreError> = (data, expiry).transaction(|(data, expiry)| {
+        if expiry.get(key)?.is_some_and(|expires_at| is_expired(&expires_at, now)) {
+            let old_value = data.remove(key)?;
+            let old_expiry = expiry.remove(key)?;
+            snapshots.save(key, old_value, old_expiry);
+        }
+        Ok(())
+    });
+    result.map_err(from_transaction_error)
+}
+
+//the value of key inside a transaction, None if it is missing or expired
+fn live_value(data: &TransactionalTree, expiry: &TransactionalTree, key: &[u8], now: u64)
+    -> std::result::Result<Option<sled::IVec>, UnabortableTransactionError>
+{
+    let value = data.get(key)?;
+    if value.is_some() && expiry.get(key)?.is_some_and(|expires_at| is_expired(&expires_at, now)) {
+        return Ok(None);
+    }
+    Ok(value)
+}
+
+//every tree of db, the expiries included, into a new db at path, for restore
+fn copy_db(db: &sled::Db, path: &Path) -> Result<()> {
+    let copy = sled::open(path)?;
+    copy.import(db.export());
+    copy.flush()?;
+    Ok(())
+}
+
+//an unreadable expiry never expires, rather than deleting the key
+fn is_expired(expires_at: &[u8], now: u64) -> bool {
+    expiry_millis(expires_at).is_some_and(|expires_at| expires_at <= now)
+}
+
+//an expiry as stored in the expiry tree, None if it is not one
+fn expiry_millis(expires_at: &[u8]) -> Option<u64> {
+    expires_at.try_into().ok().map(u64::from_le_bytes)
+}
+
+fn from_transaction_error(err: TransactionError<KVStoreError>) -> KVStoreError {
+    match err {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
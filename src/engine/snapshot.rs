@@ -0,0 +1,43 @@
+// Read-only views of a store pinned to one point in time
+// writes made after KvsEngine::snapshot returned are never seen through it
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use crate::Result;
+
+// pairs of a snapshot in byte order of their keys
+pub type SnapshotIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+// the same with the expiry of each pair, a unix time in milliseconds, None for a pair without ttl
+pub type ExpiringSnapshotIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>, Option<u64>)>> + 'a>;
+
+pub trait Snapshot: Send + 'static {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    // every pair with start <= key < end (no upper bound if end is None), with its expiry
+    fn range_with_expiry(&self, start: &[u8], end: Option<&[u8]>) -> ExpiringSnapshotIter<'_>;
+
+    // every pair with start <= key < end (no upper bound if end is None)
+    fn range_bytes(&self, start: &[u8], end: Option<&[u8]>) -> SnapshotIter<'_> {
+        Box::new(self.range_with_expiry(start, end).map(|entry| entry.map(|(key, value, _)| (key, value))))
+    }
+
+    // fails with Utf8Error if the value is not UTF-8, get_bytes reads it anyway
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+    // every pair of the snapshot
+    fn iter(&self) -> SnapshotIter<'_> {
+        self.range_bytes(&[], None)
+    }
+}
+
+// the entries of map with start <= key < end, an empty range for end <= start
+pub(crate) fn range<'a, V>(map: &'a BTreeMap<Vec<u8>, V>, start: &[u8], end: Option<&[u8]>)
+    -> std::collections::btree_map::Range<'a, Vec<u8>, V>
+{
+    //BTreeMap::range panics on a reversed range
+    let end = match end {
+        Some(end) if end <= start => start,
+        Some(end) => end,
+        None => return map.range::<[u8], _>((Bound::Included(start), Bound::Unbounded)),
+    };
+    map.range::<[u8], _>((Bound::Included(start), Bound::Excluded(end)))
+}
@@ -0,0 +1,112 @@
+// Multi-key transactions with optimistic concurrency: reads go straight to the store,
+// writes are buffered and applied at commit, unless a key that was read changed meanwhile
+use std::collections::{BTreeMap, HashMap};
+use crate::{Command, KVStoreError, KvStore, Result};
+
+// the view of the store inside KvsEngine::transaction
+// get sees the writes made earlier in the same transaction
+pub trait Transaction {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+    // fails with KeyNotFound like KvsEngine::remove
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()>;
+
+    // fails with Utf8Error if the value is not UTF-8, get_bytes reads it anyway
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+// run f again while it fails with TransactionConflict, at most max_attempts times in total
+// e.g. retry_on_conflict(5, || store.transaction(|txn| ...))
+pub fn retry_on_conflict<F, R>(max_attempts: usize, mut f: F) -> Result<R>
+where
+    F: FnMut() -> Result<R>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(KVStoreError::TransactionConflict) if attempt < max_attempts => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+// KvStore transaction: remembers the seq of every key it read and checks them at commit
+pub(crate) struct KvTransaction<'a> {
+    store: &'a KvStore,
+    // seq a key had when it was first read, None if it was missing
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // None removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> KvTransaction<'a> {
+    pub(crate) fn new(store: &'a KvStore) -> Self {
+        KvTransaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // every key read so far still has the seq it was read at
+    // checked after each read, so the reads always come from one state of the store
+    fn validate(&self) -> Result<()> {
+        for (key, seq) in &self.reads {
+            if self.store.current_seq(key) != *seq {
+                return Err(KVStoreError::TransactionConflict);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn commit(self) -> Result<()> {
+        //the reads were validated by the last get, nothing to write means nothing to check
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let commands = self.writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::SET(key, value),
+                None => Command::RM(key),
+            })
+            .collect();
+        self.store.commit_transaction(commands, self.reads.into_iter().collect())
+    }
+}
+
+impl Transaction for KvTransaction<'_> {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let entry = self.store.read_versioned(key)?;
+        let seq = entry.as_ref().map(|(_, seq)| *seq);
+        if *self.reads.entry(key.to_vec()).or_insert(seq) != seq {
+            return Err(KVStoreError::TransactionConflict);
+        }
+        self.validate()?;
+        Ok(entry.map(|(value, _)| value))
+    }
+
+    fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+//the Fail derive expands its impls inside a const block
+#![allow(non_local_definitions)]
 use failure::Fail;
 //use std::io::string;
 //use sled::Error;
@@ -14,19 +16,68 @@ pub enum KVStoreError {
     #[fail(display = "{}", _0)]
     SerdeError(#[cause] serde_json::Error),
 
+    //(3) merge Error from bincode, the encoding of the client/server protocol
+    #[fail(display = "{}", _0)]
+    CodecError(#[cause] bincode::Error),
+
     #[fail(display = "Unknown command type")]
     UnknownCommandType,
 
+    #[fail(display = "Corrupted log record: checksum or length mismatch")]
+    CorruptedRecord,
+
+    #[fail(display = "Corrupted hint file")]
+    CorruptedHint,
+
     #[fail(display = "Key not found")]
     KeyNotFound,
 
     #[fail(display = "{}", _0)]
     ServerError(String),
 
+    #[fail(display = "Unexpected response from server")]
+    UnexpectedResponse,
+
+    #[fail(display = "Connection to the server broke off in an earlier request, connect again")]
+    ConnectionBroken,
+
     //(4) merge Error from sled::Error
     #[fail(display = "{}", _0)]
     SledError(#[cause] sled::Error),
 
+    #[fail(display = "Transaction conflict: a key it read was written by someone else")]
+    TransactionConflict,
+
+    #[fail(display = "Value of key {} is not an integer", _0)]
+    NotAnInteger(String),
+
+    #[fail(display = "Incrementing key {} overflows a 64-bit integer", _0)]
+    IntegerOverflow(String),
+
+    #[fail(display = "Unsupported store format version: {}", _0)]
+    UnsupportedFormatVersion(u32),
+
+    #[fail(display = "Invalid option: {}", _0)]
+    InvalidOption(String),
+
+    #[fail(display = "Directory {} is not empty", _0)]
+    DirectoryNotEmpty(String),
+
+    #[fail(display = "Store {} is in use by another process, stop the kvs-server or kvs-admin using it first", _0)]
+    StoreLocked(String),
+
+    #[fail(display = "{} is not a complete backup", _0)]
+    InvalidBackup(String),
+
+    #[fail(display = "Backups are disabled, start the server with --backup-dir")]
+    BackupsDisabled,
+
+    #[fail(display = "Invalid backup name {}: it must name a directory right in the backup dir", _0)]
+    InvalidBackupName(String),
+
+    #[fail(display = "Invalid dump: {}", _0)]
+    InvalidDump(String),
+
     #[fail(display = "Changing engine is not allowed after initilization in current dir")]
     ChangeEngineError,
 
@@ -47,6 +98,16 @@ impl From<serde_json::Error> for KVStoreError {
     }
 }
 
+//an io error while reading a message is an io error like any other
+impl From<bincode::Error> for KVStoreError {
+    fn from(err: bincode::Error) -> Self {
+        match *err {
+            bincode::ErrorKind::Io(err) => KVStoreError::IoError(err),
+            _ => KVStoreError::CodecError(err),
+        }
+    }
+}
+
 impl From<sled::Error> for KVStoreError {
     fn from(err: sled::Error) -> Self {
         KVStoreError::SledError(err)
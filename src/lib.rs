@@ -8,6 +8,9 @@ mod engine;
 mod request;
 mod response;
 mod server;
+pub mod protocol;
+pub mod dump;
+pub mod client;
 pub mod thread_pool;
 
 pub use errors::{KVStoreError, Result};
@@ -15,6 +18,12 @@ pub use engine::KvsEngine;
 pub use engine::Command;
 pub use engine::KvStore;
 pub use engine::SledKvStore;
+pub use engine::{KvStoreOptions, SyncPolicy};
+pub use engine::CacheStats;
+pub use engine::WriteBatch;
+pub use engine::{retry_on_conflict, Transaction};
+pub use engine::{ExpiringSnapshotIter, KvSnapshot, SledSnapshot, Snapshot, SnapshotIter};
 pub use request::Request;
 pub use response::Response;
 pub use server::{EngineType,KvServer};
+pub use client::KvsClient;
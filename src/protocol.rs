@@ -0,0 +1,25 @@
+// Requests and responses go over the TcpStream bincode encoded, one after another,
+// so keys and values are sent as raw bytes
+use std::io::{Read, Write};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::Result;
+
+// a length read from the stream larger than this is rejected before anything is allocated
+const MAX_MESSAGE_LEN: u64 = 64 * 1024 * 1024;
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_LEN)
+}
+
+// write one Request or Response, the caller flushes the writer
+pub fn write_message<W: Write, T: Serialize>(writer: W, message: &T) -> Result<()> {
+    options().serialize_into(writer, message)?;
+    Ok(())
+}
+
+// read exactly one Request or Response, nothing after it is consumed
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: R) -> Result<T> {
+    Ok(options().deserialize_from(reader)?)
+}
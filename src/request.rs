@@ -1,9 +1,31 @@
 use serde::Deserialize;
 use serde::Serialize;
+use crate::WriteBatch;
 
+#[allow(non_camel_case_types)]
 #[derive(Serialize,Deserialize,Debug)]
+// keys and values are raw bytes
 pub enum Request {
-    SET(String,String),
-    RM(String),
-    GET(String),
+    SET(Vec<u8>,Vec<u8>),
+    //key, value, ttl in milliseconds
+    SET_TTL(Vec<u8>,Vec<u8>,u64),
+    RM(Vec<u8>),
+    GET(Vec<u8>),
+    //start, end (exclusive, None for no upper bound), limit
+    SCAN(Vec<u8>,Option<Vec<u8>>,usize),
+    SCAN_PREFIX(Vec<u8>),
+    //applied with KvsEngine::write_batch, answered with Ok(None)
+    BATCH(WriteBatch),
+    //keys a client transaction read with the values it saw, and its writes
+    //the writes are applied in one KvsEngine::transaction if all keys still have those values,
+    //answered with Ok(None), or Conflict if they do not
+    TXN(Vec<(Vec<u8>,Option<Vec<u8>>)>,WriteBatch),
+    //key, expected value, new value, as in KvsEngine::compare_and_swap_bytes
+    //answered with Ok(None) when swapped and Conflict when the value was not the expected one
+    CAS(Vec<u8>,Option<Vec<u8>>,Option<Vec<u8>>),
+    //key, delta, answered with Ok holding the new value
+    INCR(Vec<u8>,i64),
+    //a name, the engine writes a backup of itself with KvsEngine::backup_to into that directory
+    //of the backup dir the server was given, never anywhere else
+    BACKUP(String),
 }
\ No newline at end of file
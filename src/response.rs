@@ -4,8 +4,13 @@ use serde::Deserialize;
 #[derive(Serialize,Deserialize,Debug)]
 pub enum Response {
     //1. for succeed request
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     //2. for failed request
     Err(String),
+    //3. key/value pairs of a scan request, in key order
+    Scan(Vec<(Vec<u8>,Vec<u8>)>),
+    //4. a transaction or compare-and-swap was not applied,
+    //because a key did not have the value it expected; unlike Err nothing failed
+    Conflict,
 }
 
@@ -1,21 +1,63 @@
-use std::net::{TcpListener,TcpStream};
+use std::collections::HashMap;
+use std::net::{SocketAddr,TcpListener,TcpStream};
 use std::sync::atomic::AtomicBool;
 use crate::thread_pool::ThreadPool;
-use crate::{Result,KvsEngine,Request,Response};
-//use serde::Deserialize;
-use std::io::BufReader;
+use crate::{Command,KVStoreError,Result,KvsEngine,Request,Response,Transaction,WriteBatch};
+use crate::protocol;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::fmt;
+use std::path::{Component, Path, PathBuf};
 use log::{info,error,debug};
-use serde::Deserialize;
-use std::sync::Arc;
+use polling::{Event, Events, Poller};
+use std::sync::{Arc,Condvar,Mutex};
+use std::sync::mpsc::{self, Sender};
 use std::sync::atomic::Ordering;
-use std::time::SystemTime;
+use std::time::{Duration,Instant,SystemTime};
+
+// how long serve() waits for a connection or a request before checking the stop flag again
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+// the key of the listener in the poller, the idle connections get keys below it
+// (usize::MAX is taken by the poller for notify)
+const LISTENER_KEY: usize = usize::MAX - 1;
+// a busy connection goes back to serve() after this many requests, so it can not hold a worker forever
+const REQUESTS_PER_JOB: usize = 64;
+// the longest a request may take to arrive once it started, or its response to be sent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub enum EngineType {
     KvStore,
     SledKvStore,
 }
 
+impl EngineType {
+    //the engine of the store under dir: kvs/ or sled/, requested is the engine the user asked for
+    //an engine already initialized under dir can not be changed, a new dir gets requested or kvs
+    pub fn judge(dir: &Path, requested: Option<&str>) -> Result<EngineType> {
+        match requested {
+            None => {
+                //impl Display trait for enum EngineType
+                if dir.join(EngineType::SledKvStore.to_string()).exists() {
+                    return Ok(EngineType::SledKvStore);
+                }
+                Ok(EngineType::KvStore)
+            }
+            Some(eg) => {
+                if eg == EngineType::SledKvStore.to_string() {
+                    if dir.join(EngineType::KvStore.to_string()).exists() {
+                        return Err(KVStoreError::ChangeEngineError);
+                    }
+                    Ok(EngineType::SledKvStore)
+                } else {
+                    if dir.join(EngineType::SledKvStore.to_string()).exists() {
+                        return Err(KVStoreError::ChangeEngineError);
+                    }
+                    Ok(EngineType::KvStore)
+                }
+            }
+        }
+    }
+}
+
 //for to_string() can be used on enum EngineType when combine the current dir in kvs_server.rs
 impl fmt::Display for EngineType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -34,75 +76,433 @@ where
 {
     engine: E,
     pool: P,
+    //once set to true, serve() stops accepting, drains the in-flight jobs and returns
+    is_stop: Arc<AtomicBool>,
+    //number of handle_connection jobs spawned into the pool but not finished yet
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+    //where BACKUP requests write, None refuses them
+    backup_dir: Option<Arc<Path>>,
 }
 
 impl <E: KvsEngine, P: ThreadPool> KvServer<E,P> {
     // construct
-    pub fn new(engine: E, pool: P) -> Self {
+    pub fn new(engine: E, pool: P, is_stop: Arc<AtomicBool>) -> Self {
         KvServer { 
             engine,
             pool,
+            is_stop,
+            in_flight: Arc::new((Mutex::new(0), Condvar::new())),
+            backup_dir: None,
         }
     }
 
+    //clients may only back up into directories right in dir, without it they can not back up at all
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into().into());
+        self
+    }
+
+    //the handle can be stored anywhere (signal handler, another thread) to stop the server later
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.is_stop)
+    }
+
     //serve and listen at addr
-    //循环处理每一个stream
+    //循环处理每一个stream, until the stop flag is set
+    //an idle connection waits in the poller and only takes a pool thread once a request starts to arrive
     pub fn serve(&mut self, addr: &String) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        //the poller may report a connection another accept() took already, so accept() must not block
+        listener.set_nonblocking(true)?;
         info!("serving request and listening on [{}]", addr);
-        for stream in listener.incoming() { 
-            //clone the egine
-            let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = handle_connection(engine, stream) {
-                        error!("Unexpected error occours when serving request: {:?}", e);
-                    }}
+        let poller = Arc::new(Poller::new()?);
+        //deleted again before the listener is dropped
+        unsafe { poller.add(&listener, Event::readable(LISTENER_KEY))? };
+        //jobs send their connection back here once it is idle, and wake the poller
+        let (idle_sender, idle_receiver) = mpsc::channel();
+        let mut idle = IdleConnections::new(Arc::clone(&poller));
+        let mut events = Events::new();
+        let mut result = Ok(());
+        while !self.is_stop.load(Ordering::SeqCst) {
+            events.clear();
+            match poller.wait(&mut events, Some(STOP_CHECK_INTERVAL)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(e) => {
-                    error!("Unexpected error occours when serving request: {:?}", e);
+                    error!("can not wait for connections: {:?}", e);
+                    result = Err(e.into());
+                    break;
+                }
+            }
+            for event in events.iter() {
+                if event.key == LISTENER_KEY {
+                    self.accept_all(&listener, &mut idle);
+                    //events are one-shot, the next connection needs the listener armed again
+                    if let Err(e) = poller.modify(&listener, Event::readable(LISTENER_KEY)) {
+                        error!("can not wait for connections: {:?}", e);
+                        result = Err(e.into());
                     }
-            });
+                } else if let Some(connection) = idle.remove(event.key) {
+                    self.route(connection, &mut idle, &idle_sender);
+                }
+            }
+            if result.is_err() {
+                break;
+            }
+            for connection in idle_receiver.try_iter() {
+                self.route(connection, &mut idle, &idle_sender);
+            }
         }
-        Ok(())
+        info!("stop flag received, no more connections accepted");
+        if let Err(e) = poller.delete(&listener) {
+            error!("can not remove the listener from the poller: {:?}", e);
+        }
+        drop(listener);
+        //nothing is pending on the idle connections, close them right away
+        drop(idle);
+
+        self.wait_in_flight();
+        self.engine.flush()?;
+        info!("all in-flight requests served and engine flushed, server stopped");
+        result
     } 
+
+    //every connection waiting to be accepted goes to the idle ones, its first request has yet to arrive
+    fn accept_all(&self, listener: &TcpListener, idle: &mut IdleConnections) {
+        loop {
+            match listener.accept() {
+                Ok((stream, _)) => match Connection::new(stream) {
+                    Ok(connection) => if let Err(e) = idle.insert(connection) {
+                        error!("Unexpected error occours when accepting connection: {:?}", e);
+                    },
+                    Err(e) => error!("Unexpected error occours when accepting connection: {:?}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Unexpected error occours when accepting connection: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    //hand a connection whose next request has started to arrive to the pool, keep an idle one in the poller
+    //a connection coming back from a job may have the request in its buffer already, which the poller never reports
+    fn route(&self, mut connection: Connection, idle: &mut IdleConnections, idle_sender: &Sender<Connection>) {
+        match connection.poll() {
+            Ok(Readiness::Ready) => self.dispatch(connection, idle_sender.clone(), Arc::clone(&idle.poller)),
+            Ok(Readiness::Idle) => if let Err(e) = idle.insert(connection) {
+                error!("Unexpected error occours when serving request: {:?}", e);
+            },
+            Ok(Readiness::Closed) => debug!("connection closed: {:?}", connection.peer_addr()),
+            Err(e) => error!("Unexpected error occours when serving request: {:?}", e),
+        }
+    }
+
+    fn dispatch(&self, connection: Connection, idle_sender: Sender<Connection>, poller: Arc<Poller>) {
+        //clone the egine
+        let engine = self.engine.clone();
+        let is_stop = Arc::clone(&self.is_stop);
+        let backup_dir = self.backup_dir.clone();
+        let guard = InFlightGuard::new(Arc::clone(&self.in_flight));
+        self.pool.spawn(move || {
+            //moved into the job, so the counter is released even if the job panics
+            let _guard = guard;
+            match handle_connection(engine, backup_dir.as_deref(), connection, &is_stop) {
+                //serve() is gone if sending fails, the connection is closed with it
+                Ok(Some(connection)) => {
+                    if idle_sender.send(connection).is_ok() {
+                        if let Err(e) = poller.notify() {
+                            error!("can not wake the server up for an idle connection: {:?}", e);
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => error!("Unexpected error occours when serving request: {:?}", e),
+            }
+        });
+    }
+
+    //block until every spawned handle_connection job has finished
+    fn wait_in_flight(&self) {
+        let (count, cvar) = &*self.in_flight;
+        let mut count = count.lock().unwrap();
+        while *count > 0 {
+            debug!("waiting for {} in-flight connections", *count);
+            count = cvar.wait(count).unwrap();
+        }
+    }
+}
+
+//counts one in-flight job from creation until it is dropped
+struct InFlightGuard {
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl InFlightGuard {
+    fn new(in_flight: Arc<(Mutex<usize>, Condvar)>) -> Self {
+        *in_flight.0.lock().unwrap() += 1;
+        InFlightGuard { in_flight }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let (count, cvar) = &*self.in_flight;
+        let mut count = count.lock().unwrap();
+        *count -= 1;
+        cvar.notify_all();
+    }
+}
+
+// the connections serve() holds until a request starts to arrive on them, each registered with the poller
+// a registration must be deleted before its socket is closed, so they only leave through remove() or drop
+struct IdleConnections {
+    poller: Arc<Poller>,
+    connections: HashMap<usize, Connection>,
+    next_key: usize,
+}
+
+impl IdleConnections {
+    fn new(poller: Arc<Poller>) -> Self {
+        IdleConnections { poller, connections: HashMap::new(), next_key: 0 }
+    }
+
+    // reported once by the poller when its next request starts to arrive or it is closed
+    fn insert(&mut self, connection: Connection) -> io::Result<()> {
+        let key = self.next_key;
+        self.next_key = (self.next_key + 1) % LISTENER_KEY;
+        //deleted again in remove or drop, before the connection is closed
+        unsafe { self.poller.add(connection.reader.get_ref(), Event::readable(key))? };
+        self.connections.insert(key, connection);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: usize) -> Option<Connection> {
+        let connection = self.connections.remove(&key)?;
+        if let Err(e) = self.poller.delete(connection.reader.get_ref()) {
+            error!("can not remove a connection from the poller: {:?}", e);
+        }
+        Some(connection)
+    }
+}
+
+impl Drop for IdleConnections {
+    fn drop(&mut self) {
+        for connection in self.connections.values() {
+            if let Err(e) = self.poller.delete(connection.reader.get_ref()) {
+                error!("can not remove a connection from the poller: {:?}", e);
+            }
+        }
+    }
+}
+
+// a client connection, owned by serve() while idle and by a pool job while it has requests
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+enum Readiness {
+    // a request has started to arrive
+    Ready,
+    Idle,
+    // the client closed the connection
+    Closed,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Result<Connection> {
+        info!("tcpstream: {:?}", &stream);
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.writer.get_ref().peer_addr()
+    }
+
+    // whether the next request has started to arrive, without waiting for it
+    fn poll(&mut self) -> Result<Readiness> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(Readiness::Ready);
+        }
+        self.reader.get_ref().set_nonblocking(true)?;
+        match self.reader.fill_buf() {
+            Ok([]) => Ok(Readiness::Closed),
+            Ok(_) => Ok(Readiness::Ready),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Readiness::Idle),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // read the request that has started to arrive, failing once REQUEST_TIMEOUT has passed
+    fn read_request(&mut self) -> Result<Request> {
+        let stream = self.reader.get_ref();
+        stream.set_nonblocking(false)?;
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        protocol::read_message(DeadlineReader { reader: &mut self.reader, deadline })
+    }
 }
 
-// deserialize the stream to data gram strcut
-// call from struct
-fn handle_connection<E: KvsEngine> (engine: E, mut stream: TcpStream) -> Result<()> {
-    let request = Request::deserialize(&mut serde_json::Deserializer::from_reader(BufReader::new(&mut stream)))?;
-    info!("tcpstream: {:?}", &stream);
-    let bufreader = BufReader::new(&mut stream);
-    info!("bufreader: {:?}",&bufreader);
+// reads from a connection until deadline, so a client that stalls in the middle of a request
+// can not hold a worker, or the drain of a stopping server, forever
+struct DeadlineReader<'a> {
+    reader: &'a mut BufReader<TcpStream>,
+    deadline: Instant,
+}
 
-    let now = SystemTime::now();
-    debug!("Request: {:?}", &request);
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        //only a read reaching the socket can block
+        if self.reader.buffer().is_empty() {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request not received in time"));
+            }
+            self.reader.get_ref().set_read_timeout(Some(remaining))?;
+        }
+        self.reader.read(buf)
+    }
+}
+
+// serve the requests of a connection in order while they keep coming
+// returns the connection once it is idle, None once it is closed or the server is stopping
+fn handle_connection<E: KvsEngine> (engine: E, backup_dir: Option<&Path>, mut connection: Connection, is_stop: &AtomicBool)
+    -> Result<Option<Connection>>
+{
+    for _ in 0..REQUESTS_PER_JOB {
+        let request = connection.read_request()?;
+        let now = SystemTime::now();
+        debug!("Request: {:?}", &request);
 
-    let response;
+        let response = execute(&engine, backup_dir, request);
+        debug!("Response: {:?},spent time: {:?}", &response, now.elapsed());
+
+        protocol::write_message(&mut connection.writer, &response)?;
+        //the client waits for this response before sending the next request
+        connection.writer.flush()?;
+
+        if is_stop.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        //a client sending requests back to back keeps the worker, one whose next request is not here yet
+        //goes back to the poller
+        match connection.poll()? {
+            Readiness::Ready => {}
+            Readiness::Idle => return Ok(Some(connection)),
+            Readiness::Closed => {
+                debug!("connection closed: {:?}", connection.peer_addr());
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(connection))
+}
+
+fn execute<E: KvsEngine>(engine: &E, backup_dir: Option<&Path>, request: Request) -> Response {
     match request {
        Request::GET(key) => {
-           match engine.get(key) {
-               Ok(value) => response = Response::Ok(value),
-               Err(err) => response = Response::Err(err.to_string()),
+           match engine.get_bytes(&key) {
+               Ok(value) => Response::Ok(value),
+               Err(err) => Response::Err(err.to_string()),
            }
        }
        Request::SET(key, val) => {
-           match engine.set(key, val) {
-               Ok(()) => response = Response::Ok(None),
-               Err(err) => response = Response::Err(err.to_string()),
+           match engine.set_bytes(&key, &val) {
+               Ok(()) => Response::Ok(None),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::SET_TTL(key, val, ttl_ms) => {
+           match engine.set_with_ttl_bytes(&key, &val, Duration::from_millis(ttl_ms)) {
+               Ok(()) => Response::Ok(None),
+               Err(err) => Response::Err(err.to_string()),
            }
        }
        Request::RM(key) => {
-           match engine.remove(key) {
-               Ok(()) => response = Response::Ok(None),
-               Err(err) => response = Response::Err(err.to_string()),
+           match engine.remove_bytes(&key) {
+               Ok(()) => Response::Ok(None),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::SCAN(start, end, limit) => {
+           match engine.scan_bytes(&start, end.as_deref(), limit) {
+               Ok(pairs) => Response::Scan(pairs),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::SCAN_PREFIX(prefix) => {
+           match engine.scan_prefix_bytes(&prefix) {
+               Ok(pairs) => Response::Scan(pairs),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::BATCH(batch) => {
+           match engine.write_batch(batch) {
+               Ok(()) => Response::Ok(None),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::TXN(reads, batch) => {
+           match engine.transaction(|txn| apply_transaction(txn, &reads, &batch)) {
+               Ok(()) => Response::Ok(None),
+               Err(KVStoreError::TransactionConflict) => Response::Conflict,
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::INCR(key, delta) => {
+           match engine.incr_bytes(&key, delta) {
+               Ok(value) => Response::Ok(Some(value.to_string().into_bytes())),
+               Err(err) => Response::Err(err.to_string()),
            }
        }
+       Request::BACKUP(name) => {
+           match backup_path(backup_dir, &name).and_then(|path| engine.backup_to(path)) {
+               Ok(()) => Response::Ok(None),
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+       Request::CAS(key, expected, new) => {
+           match engine.compare_and_swap_bytes(&key, expected.as_deref(), new.as_deref()) {
+               Ok(true) => Response::Ok(None),
+               Ok(false) => Response::Conflict,
+               Err(err) => Response::Err(err.to_string()),
+           }
+       }
+    }
+}
+
+//any client may send BACKUP, so it only picks a directory right in the backup dir
+fn backup_path(backup_dir: Option<&Path>, name: &str) -> Result<PathBuf> {
+    let backup_dir = backup_dir.ok_or(KVStoreError::BackupsDisabled)?;
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(backup_dir.join(name)),
+        _ => Err(KVStoreError::InvalidBackupName(name.to_owned())),
     }
-   
-   debug!("Response: {:?},spent time: {:?}", &response, now.elapsed());
+}
 
-   serde_json::to_writer(stream, &response)?;
-   
-   Ok(())
+fn apply_transaction(txn: &mut dyn Transaction, reads: &[(Vec<u8>, Option<Vec<u8>>)], batch: &WriteBatch) -> Result<()> {
+    for (key, value) in reads {
+        if txn.get_bytes(key)? != *value {
+            return Err(KVStoreError::TransactionConflict);
+        }
+    }
+    for command in batch.commands() {
+        match command {
+            Command::SET(key, value) => txn.set_bytes(key, value)?,
+            //a Transaction has no ttl, so neither do the writes of a TXN request
+            Command::SET_TTL(..) => return Err(KVStoreError::UnknownCommandType),
+            //like in a WriteBatch, deleting a missing key is not an error
+            Command::RM(key) => match txn.remove_bytes(key) {
+                Err(KVStoreError::KeyNotFound) => {}
+                result => result?,
+            },
+        }
+    }
+    Ok(())
 }
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod thread_pool;
 mod naive_thread_pool;
 mod shared_queue_thread_pool;
@@ -9,7 +9,7 @@ impl ThreadPool for RayonThreadPool {
     fn new(thread: usize) -> Result<Self>
         where Self: Sized,
     {
-        let raypool = rayon::ThreadPoolBuilder::new().num_threads(thread.try_into().unwrap()).build().unwrap();
+        let raypool = rayon::ThreadPoolBuilder::new().num_threads(thread).build().unwrap();
         Ok(RayonThreadPool { raypool })
     }
 
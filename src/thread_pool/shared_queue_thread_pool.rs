@@ -25,7 +25,7 @@ impl ThreadPool for SharedQueueThreadPool {
         //if num of thread was specified less than 1, invoke panic
         assert!(thread_num > 0);
 
-        let mut workers = Vec::with_capacity(thread_num.try_into().unwrap());
+        let mut workers = Vec::with_capacity(thread_num);
 
         let (sender, receiver) = mpsc::channel();
         
@@ -37,7 +37,7 @@ impl ThreadPool for SharedQueueThreadPool {
             //std::sync::mpsc::Receiver<Job>  cannot be shared between threads safely
             //wrap it as Mutex, Mutex will ensure that only one worker gets a job from the receiver at a time.
             //In SharedQueueThreadPool::new, we put the receiver in an Arc and a Mutex. For each new worker, we clone the Arc to bump the reference count so the workers can share ownership of the receiver.
-            let worker = Worker::new(id.try_into().unwrap(), Arc::clone(&receiver))?;
+            let worker = Worker::new(id, Arc::clone(&receiver))?;
             workers.push(worker);
         }
 
@@ -114,7 +114,7 @@ impl Worker {
         //Loop: closure to loop forever, asking the receiving end of the channel for a job and running the job when it gets one. 
         let handle = thread::Builder::new().spawn(move || loop{
             //Blocking: blocks this thread and waiting availale job received
-            let message = receiver.lock().expect(&format!("mutex poisoned in thread {}", id)).recv();
+            let message = receiver.lock().unwrap_or_else(|_| panic!("mutex poisoned in thread {}", id)).recv();
             
             match message {
                Ok(Message::NewJob(message)) => {
@@ -0,0 +1,310 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::protocol::{read_message, write_message};
+use kvs::{KVStoreError, KvServer, KvStore, KvsClient, KvsEngine, Request, Response, Result, WriteBatch};
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start an in-process server on `addr`, stopped by setting the returned flag
+fn start_server(temp_dir: &TempDir, addr: &str) -> Result<(Arc<AtomicBool>, JoinHandle<Result<()>>)> {
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let is_stop = server.shutdown_handle();
+    let addr = addr.to_owned();
+    let handle = thread::spawn(move || server.serve(&addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok((is_stop, handle))
+}
+
+fn stop_server(is_stop: Arc<AtomicBool>, handle: JoinHandle<Result<()>>) -> Result<()> {
+    is_stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")
+}
+
+#[test]
+fn client_set_get_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4020")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4020")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4024")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4024")?;
+    for i in 0..5 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+
+    let pairs = client.scan("key1".to_owned(), Some("key3".to_owned()), 10)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(client.scan(String::new(), None, 4)?.len(), 4);
+    assert_eq!(client.scan_prefix("key".to_owned())?.len(), 5);
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+// Errors of the engine should come back as `KVStoreError::ServerError`
+#[test]
+fn client_remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4021")?;
+
+    let mut client = KvsClient::connect_timeout("127.0.0.1:4021", Duration::from_secs(5))?;
+    match client.remove("key1".to_owned()) {
+        Err(KVStoreError::ServerError(err)) => assert!(err.contains("Key not found")),
+        other => panic!("unexpected result {:?}", other),
+    }
+    // the connection is still usable after an error response
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+#[test]
+fn client_concurrent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4022")?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect("127.0.0.1:4022")?;
+                for i in 0..50 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    client.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    stop_server(is_stop, handle)
+}
+
+// A server that never answers should make the request fail after the timeout
+#[test]
+fn client_read_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4023")?;
+    let mut client = KvsClient::connect_timeout("127.0.0.1:4023", Duration::from_millis(200))?;
+    match client.get("key1".to_owned()) {
+        Err(KVStoreError::IoError(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    drop(listener);
+    Ok(())
+}
+
+// The late response of a timed out request must not be taken as the answer of the next one
+#[test]
+fn client_broken_after_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4029")?;
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let _: Request = read_message(&mut stream)?;
+        thread::sleep(Duration::from_millis(400));
+        write_message(&mut stream, &Response::Ok(Some(b"late".to_vec())))?;
+        stream.flush()?;
+        Ok(())
+    });
+
+    let mut client = KvsClient::connect_timeout("127.0.0.1:4029", Duration::from_millis(200))?;
+    match client.get("key1".to_owned()) {
+        Err(KVStoreError::IoError(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.join().unwrap()?;
+    match client.get("key2".to_owned()) {
+        Err(KVStoreError::ConnectionBroken) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn client_connect_invalid_addr() {
+    assert!(KvsClient::connect("invalid-addr").is_err());
+    assert!(KvsClient::connect_timeout("invalid-addr", Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn client_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4025")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4025")?;
+    client.set("key0".to_owned(), "value0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key1".to_owned(), "value1".to_owned())
+        .put("key2".to_owned(), "value2".to_owned())
+        .delete("key0".to_owned());
+    client.write_batch(batch)?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert_eq!(client.scan_prefix("key".to_owned())?.len(), 2);
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+// The server applies a client transaction only if the values it read are unchanged
+#[test]
+fn client_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4026")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4026")?;
+    let mut other = KvsClient::connect("127.0.0.1:4026")?;
+    client.set("a".to_owned(), "1".to_owned())?;
+
+    client.transaction(|txn| {
+        let a = txn.get("a".to_owned())?.unwrap();
+        txn.set("b".to_owned(), a)?;
+        txn.remove("a".to_owned())
+    })?;
+    assert_eq!(client.get("a".to_owned())?, None);
+    assert_eq!(client.get("b".to_owned())?, Some("1".to_owned()));
+
+    let result = client.transaction(|txn| {
+        txn.get("b".to_owned())?;
+        other.set("b".to_owned(), "2".to_owned())?;
+        txn.set("c".to_owned(), "3".to_owned())
+    });
+    assert!(matches!(result, Err(KVStoreError::TransactionConflict)));
+    assert_eq!(client.get("c".to_owned())?, None);
+    drop(client);
+    drop(other);
+
+    stop_server(is_stop, handle)
+}
+
+#[test]
+fn client_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4027")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4027")?;
+    assert!(client.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!client.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(client.set_if_equals("key1".to_owned(), "value1".to_owned(), "value2".to_owned())?);
+    assert!(!client.remove_if_equals("key1".to_owned(), "value1".to_owned())?);
+    assert!(client.remove_if_equals("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+// Binary keys and values go over the wire unchanged
+#[test]
+fn client_binary_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4028")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4028")?;
+    let value: Vec<u8> = (0..=255).collect();
+    client.set_bytes(&[0x00, 0xff], &value)?;
+    client.set_bytes(&[0x00, 0xfe], b"")?;
+    assert_eq!(client.get_bytes(&[0x00, 0xff])?, Some(value.clone()));
+    assert_eq!(client.get_bytes(&[0x00, 0xfe])?, Some(Vec::new()));
+    assert_eq!(
+        client.scan_prefix_bytes(&[0x00])?,
+        vec![(vec![0x00, 0xfe], Vec::new()), (vec![0x00, 0xff], value)]
+    );
+    client.remove_bytes(&[0x00, 0xff])?;
+    assert_eq!(client.scan_bytes(&[0x00], None, 10)?.len(), 1);
+
+    // as are the requests of ttl, compare and swap, incr and transactions
+    client.set_with_ttl_bytes(&[0xff, 0x01], &[0x80], Duration::from_secs(60))?;
+    assert!(client.compare_and_swap_bytes(&[0xff, 0x01], Some(&[0x80]), Some(&[0xfe]))?);
+    assert!(!client.compare_and_swap_bytes(&[0xff, 0x01], Some(&[0x80]), None)?);
+    assert_eq!(client.incr_bytes(&[0xff, 0x02], 3)?, 3);
+    client.transaction(|txn| {
+        let value = txn.get_bytes(&[0xff, 0x01])?.unwrap_or_default();
+        txn.set_bytes(&[0xff, 0x03], &value)?;
+        txn.remove_bytes(&[0xff, 0x01])
+    })?;
+    assert_eq!(
+        client.scan_prefix_bytes(&[0xff])?,
+        vec![(vec![0xff, 0x02], b"3".to_vec()), (vec![0xff, 0x03], vec![0xfe])]
+    );
+    drop(client);
+
+    stop_server(is_stop, handle)
+}
+
+// A client can only have backups written right into the backup dir of the server
+#[test]
+fn client_backup_only_into_backup_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (is_stop, handle) = start_server(&temp_dir, "127.0.0.1:4030")?;
+    let mut client = KvsClient::connect("127.0.0.1:4030")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    match client.backup("backup".to_owned()) {
+        Err(KVStoreError::ServerError(err)) => assert!(err.contains("disabled"), "unexpected error {}", err),
+        other => panic!("backup without a backup dir returned {:?}", other),
+    }
+    drop(client);
+    stop_server(is_stop, handle)?;
+
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    ).backup_dir(backup_dir.path());
+    let is_stop = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(&"127.0.0.1:4030".to_owned()));
+    thread::sleep(Duration::from_millis(500));
+    let mut client = KvsClient::connect("127.0.0.1:4030")?;
+    let outside = temp_dir.path().join("outside");
+    for name in ["../escape", "nested/backup", "..", "", outside.to_str().unwrap()] {
+        match client.backup(name.to_owned()) {
+            Err(KVStoreError::ServerError(err)) => assert!(err.contains("Invalid backup name"), "unexpected error {}", err),
+            other => panic!("backup to {:?} returned {:?}", name, other),
+        }
+    }
+    assert!(!outside.exists());
+    assert!(!backup_dir.path().parent().unwrap().join("escape").exists());
+    client.backup("backup".to_owned())?;
+    drop(client);
+    stop_server(is_stop, handle)?;
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::restore(backup_dir.path().join("backup"), restore_dir.path().join("kvs"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,213 @@
+// Crash recovery of compaction: each test rebuilds the directory a crash at one
+// step of the compaction would leave behind, then checks `KvStore::open`.
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// `before` holds the logs right before a compaction, `after` the result of it
+struct CompactionStates {
+    before: TempDir,
+    after: TempDir,
+}
+
+fn write_data(store: &KvStore) -> Result<()> {
+    for i in 0..10 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    for i in 0..5 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+    Ok(())
+}
+
+fn check_data(store: &KvStore) -> Result<()> {
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("new".to_owned()));
+    }
+    for i in 5..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("old".to_owned()));
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, to.join(path.file_name().unwrap())).unwrap();
+    }
+}
+
+fn compaction_states() -> Result<CompactionStates> {
+    let before = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(before.path(), KvStoreOptions::new().compaction_threshold(u64::MAX))?;
+    write_data(&store)?;
+    drop(store);
+
+    // 5 overwritten sets of 20 bytes stay under the threshold, the remove of key9 adds
+    // 20 + 17 stale bytes, so only the last write triggers the compaction of data_0.txt
+    // into data_1.txt, new writes would go to data_2.txt
+    let after = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(120).compaction_ratio(0.0);
+    let store = KvStore::open_with(after.path(), options)?;
+    write_data(&store)?;
+    drop(store);
+    assert!(after.path().join("data_1.txt").exists());
+    assert!(!after.path().join("data_0.txt").exists());
+
+    Ok(CompactionStates { before, after })
+}
+
+// the state the commit point leaves: the manifest of `after` with the compaction into
+// data_1.txt still pending, and data_2.txt, the active file since the compaction was scheduled
+fn write_pending_manifest(dir: &Path, after: &Path) {
+    let mut manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(after.join("MANIFEST")).unwrap()).unwrap();
+    manifest["pending_compaction"] = 1.into();
+    fs::write(dir.join("MANIFEST"), manifest.to_string()).unwrap();
+    fs::copy(after.join("data_2.txt"), dir.join("data_2.txt")).unwrap();
+}
+
+fn tmp_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+        .count()
+}
+
+// Crash while writing the compacted file: the temp file is dropped, the old logs are used
+#[test]
+fn crash_before_commit() -> Result<()> {
+    let states = compaction_states()?;
+    let dir = states.before.path();
+    let compacted = fs::read(states.after.path().join("data_1.txt"))?;
+    fs::write(dir.join("data_1.txt.tmp"), &compacted[..compacted.len() / 2])?;
+
+    let store = KvStore::open(dir)?;
+    check_data(&store)?;
+    assert_eq!(tmp_files(dir), 0);
+    assert!(dir.join("data_0.txt").exists());
+    Ok(())
+}
+
+// Crash after the manifest recorded the compaction but before the rename
+#[test]
+fn crash_after_commit_before_rename() -> Result<()> {
+    let states = compaction_states()?;
+    let dir = states.before.path();
+    fs::copy(states.after.path().join("data_1.txt"), dir.join("data_1.txt.tmp"))?;
+    write_pending_manifest(dir, states.after.path());
+
+    let store = KvStore::open(dir)?;
+    check_data(&store)?;
+    assert_eq!(tmp_files(dir), 0);
+    assert!(!dir.join("data_0.txt").exists());
+    assert!(dir.join("data_1.txt").exists());
+    Ok(())
+}
+
+// Crash after the rename but before the sealed files were deleted
+#[test]
+fn crash_after_rename_before_delete() -> Result<()> {
+    let states = compaction_states()?;
+    let dir = states.before.path();
+    fs::copy(states.after.path().join("data_1.txt"), dir.join("data_1.txt"))?;
+    write_pending_manifest(dir, states.after.path());
+
+    let store = KvStore::open(dir)?;
+    check_data(&store)?;
+    assert!(!dir.join("data_0.txt").exists());
+    drop(store);
+
+    // nothing is pending anymore
+    let store = KvStore::open(dir)?;
+    check_data(&store)?;
+    Ok(())
+}
+
+// Crash after the deletions but before the manifest was cleared
+#[test]
+fn crash_before_manifest_cleared() -> Result<()> {
+    let states = compaction_states()?;
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.after.path(), dir.path());
+    write_pending_manifest(dir.path(), states.after.path());
+
+    let store = KvStore::open(dir.path())?;
+    check_data(&store)?;
+    Ok(())
+}
+
+fn flip_last_byte(path: &Path) {
+    let mut bytes = fs::read(path).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    fs::write(path, bytes).unwrap();
+}
+
+// The compacted log gets a hint, which open loads instead of reading the values
+#[test]
+fn open_uses_hint() -> Result<()> {
+    let states = compaction_states()?;
+    assert!(states.after.path().join("data_1.hint").exists());
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.after.path(), dir.path());
+
+    // a corrupted value only shows up when it is read
+    flip_last_byte(&dir.path().join("data_1.txt"));
+    let store = KvStore::open(dir.path())?;
+    let errors = (0..10)
+        .filter(|i| store.get(format!("key{}", i)).is_err())
+        .count();
+    assert_eq!(errors, 1);
+    drop(store);
+
+    // without the hint the full replay finds it
+    fs::remove_file(dir.path().join("data_1.hint"))?;
+    assert!(KvStore::open(dir.path()).is_err());
+    Ok(())
+}
+
+// A hint that fails its checksum or is cut short is ignored
+#[test]
+fn corrupted_hint_falls_back_to_replay() -> Result<()> {
+    let states = compaction_states()?;
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    copy_dir(states.after.path(), dir.path());
+    let hint_path = dir.path().join("data_1.hint");
+    let hint = fs::read(&hint_path)?;
+
+    flip_last_byte(&hint_path);
+    let store = KvStore::open(dir.path())?;
+    check_data(&store)?;
+    drop(store);
+
+    fs::write(&hint_path, &hint[..hint.len() / 2])?;
+    let store = KvStore::open(dir.path())?;
+    check_data(&store)?;
+    Ok(())
+}
+
+// The hint goes away together with its log
+#[test]
+fn hint_removed_with_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(120).compaction_ratio(0.0);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    write_data(&store)?;
+    // dropping the store waits for the background compaction
+    drop(store);
+    assert!(temp_dir.path().join("data_1.hint").exists());
+
+    // the same writes again make data_1.txt stale
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    write_data(&store)?;
+    drop(store);
+
+    assert!(!temp_dir.path().join("data_1.txt").exists());
+    assert!(!temp_dir.path().join("data_1.hint").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    check_data(&store)?;
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use kvs::dump::{self, DumpFormat};
+use kvs::{KVStoreError, KvStore, KvStoreOptions, KvsEngine, Result, SledKvStore, Snapshot};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn fill(store: &impl KvsEngine, n: usize) -> Result<()> {
+    for i in 0..n {
+        store.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    // neither key nor value is UTF-8
+    store.set_bytes(&[0xff, 0x00], &[0x80, 0x81])?;
+    Ok(())
+}
+
+// A dump of a KvStore loads into a SledKvStore and back, in both formats
+#[test]
+fn export_import_across_engines() -> Result<()> {
+    for format in [DumpFormat::Jsonl, DumpFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let kvs = KvStore::open(temp_dir.path().join("kvs"))?;
+        fill(&kvs, 2500)?;
+
+        let mut dumped = Vec::new();
+        let mut checkpoints = Vec::new();
+        let count = dump::export(&kvs, &mut dumped, format, None, |count, key| checkpoints.push((count, key.to_vec())))?;
+        assert_eq!(count, 2501);
+        assert_eq!(checkpoints.len(), 3);
+        assert_eq!(checkpoints[0], (1000, b"key00999".to_vec()));
+        assert_eq!(checkpoints[2], (2501, vec![0xff, 0x00]));
+
+        let sled = SledKvStore::open(temp_dir.path().join("sled"))?;
+        assert_eq!(dump::import(&sled, &dumped[..], format, None, |_, _| {})?, 2501);
+        assert_eq!(sled.scan_bytes(&[], None, usize::MAX)?, kvs.scan_bytes(&[], None, usize::MAX)?);
+
+        let mut again = Vec::new();
+        dump::export(&sled, &mut again, format, None, |_, _| {})?;
+        assert_eq!(again, dumped);
+    }
+    Ok(())
+}
+
+// An import resumed after a checkpoint key loads the pairs after it only
+#[test]
+fn resume_after_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path().join("source"))?;
+    fill(&source, 10)?;
+    let mut dumped = Vec::new();
+    dump::export(&source, &mut dumped, DumpFormat::Jsonl, None, |_, _| {})?;
+
+    let target = KvStore::open(temp_dir.path().join("target"))?;
+    let resume_after = dump::parse_key("\"key00004\"");
+    assert_eq!(dump::import(&target, &dumped[..], DumpFormat::Jsonl, Some(&resume_after), |_, _| {})?, 6);
+    assert_eq!(target.get("key00004".to_owned())?, None);
+    assert_eq!(target.get("key00005".to_owned())?, Some("value5".to_owned()));
+
+    // export resumes the same way
+    let mut rest = Vec::new();
+    let binary_key = dump::parse_key(&dump::display_key(&[0xff, 0x00]));
+    assert_eq!(binary_key, vec![0xff, 0x00]);
+    assert_eq!(dump::export(&source, &mut rest, DumpFormat::Binary, Some(b"key00008"), |_, _| {})?, 2);
+    assert_eq!(dump::parse_key("key00008"), b"key00008".to_vec());
+    Ok(())
+}
+
+// Expired keys the sweeper has not removed yet are left out of a dump
+#[test]
+fn export_skips_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // no sweep removes the expired keys before the export gets to them
+    let options = KvStoreOptions::new().expiry_sweep_interval(Duration::from_secs(60));
+    let kvs = KvStore::open_with(temp_dir.path().join("kvs"), options.clone())?;
+    let sled = SledKvStore::open_with(temp_dir.path().join("sled"), options)?;
+    for i in 0..1500 {
+        let key = format!("expired{:05}", i);
+        kvs.set_with_ttl(key.clone(), "value".to_owned(), Duration::from_millis(1))?;
+        sled.set_with_ttl(key, "value".to_owned(), Duration::from_millis(1))?;
+    }
+    fill(&kvs, 2500)?;
+    fill(&sled, 2500)?;
+    thread::sleep(Duration::from_millis(10));
+
+    let mut dumped = Vec::new();
+    assert_eq!(dump::export(&kvs, &mut dumped, DumpFormat::Binary, None, |_, _| {})?, 2501);
+    let mut again = Vec::new();
+    assert_eq!(dump::export(&sled, &mut again, DumpFormat::Binary, None, |_, _| {})?, 2501);
+    assert_eq!(again, dumped);
+    Ok(())
+}
+
+// A key keeps its expiry through a dump, one that expired since the export is not imported
+#[test]
+fn export_import_expiries() -> Result<()> {
+    for format in [DumpFormat::Jsonl, DumpFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let kvs = KvStore::open(temp_dir.path().join("kvs"))?;
+        fill(&kvs, 10)?;
+        kvs.set_with_ttl("key00003".to_owned(), "value3".to_owned(), Duration::from_secs(3600))?;
+        kvs.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(500))?;
+        let mut dumped = Vec::new();
+        assert_eq!(dump::export(&kvs, &mut dumped, format, None, |_, _| {})?, 12);
+
+        let sled = SledKvStore::open(temp_dir.path().join("sled"))?;
+        assert_eq!(dump::import(&sled, &dumped[..], format, None, |_, _| {})?, 12);
+        let expiries = |snapshot: &dyn Snapshot| -> Result<Vec<(Vec<u8>, Option<u64>)>> {
+            snapshot.range_with_expiry(&[], None).map(|pair| pair.map(|(key, _, expires_at)| (key, expires_at))).collect()
+        };
+        let imported = expiries(&sled.snapshot()?)?;
+        assert_eq!(imported, expiries(&kvs.snapshot()?)?);
+        assert!(imported[3].1.is_some());
+        assert!(imported[4].1.is_none());
+
+        thread::sleep(Duration::from_millis(600));
+        let target = KvStore::open(temp_dir.path().join("target"))?;
+        assert_eq!(dump::import(&target, &dumped[..], format, None, |_, _| {})?, 11);
+        assert_eq!(target.get("short".to_owned())?, None);
+        assert_eq!(expiries(&target.snapshot()?)?, expiries(&kvs.snapshot()?)?);
+    }
+    Ok(())
+}
+
+// A binary dump written before dumps carried expiries still imports
+#[test]
+fn import_dump_without_expiries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut dumped = b"KVSDUMP1".to_vec();
+    for data in [&b"key1"[..], b"value1"] {
+        dumped.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        dumped.extend_from_slice(data);
+    }
+    assert_eq!(dump::import(&store, &dumped[..], DumpFormat::Binary, None, |_, _| {})?, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A damaged dump fails the import instead of loading garbage
+#[test]
+fn invalid_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match dump::import(&store, &b"NOTADUMP"[..], DumpFormat::Binary, None, |_, _| {}) {
+        Err(KVStoreError::InvalidDump(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    let jsonl = b"{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":1}\n";
+    match dump::import(&store, &jsonl[..], DumpFormat::Jsonl, None, |_, _| {}) {
+        Err(KVStoreError::InvalidDump(err)) => assert!(err.starts_with("line 2")),
+        other => panic!("unexpected result {:?}", other),
+    }
+    // a binary dump cut in the middle of a pair
+    let mut dumped = Vec::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    dump::export(&store, &mut dumped, DumpFormat::Binary, None, |_, _| {})?;
+    dumped.pop();
+    match dump::import(&store, &dumped[..], DumpFormat::Binary, None, |_, _| {}) {
+        Err(KVStoreError::IoError(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
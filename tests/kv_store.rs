@@ -1,6 +1,11 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{retry_on_conflict, CacheStats, KVStoreError, KvStore, KvStoreOptions, KvsEngine, Result, Snapshot, SyncPolicy, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -213,4 +218,1144 @@ fn concurrent_get() -> Result<()> {
     }
     println!("thrid round -get endss.");
     Ok(())
-}
\ No newline at end of file
+}
+
+// Should return pairs in key order within [start, end), up to limit
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..10).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let pairs = store.scan("key3".to_owned(), Some("key7".to_owned()), 100)?;
+    let expected: Vec<(String, String)> = [3, 4, 6]
+        .iter()
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    let pairs = store.scan("key8".to_owned(), None, 100)?;
+    assert_eq!(pairs.len(), 2);
+    assert_eq!(store.scan(String::new(), None, 3)?.len(), 3);
+    assert!(store.scan("key7".to_owned(), Some("key3".to_owned()), 100)?.is_empty());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(String::new(), None, 100)?.len(), 9);
+    Ok(())
+}
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("user:".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert!(store.scan_prefix("none".to_owned())?.is_empty());
+    Ok(())
+}
+
+// Log files written by the store, largest first
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.expect("fail to walk directory").into_path())
+        .filter(|path| path.extension() == Some("txt".as_ref()))
+        .collect();
+    files.sort_by_key(|path| std::cmp::Reverse(fs::metadata(path).unwrap().len()));
+    files
+}
+
+// A flipped bit inside a record followed by other records should be reported
+// instead of returning a wrong value
+#[test]
+fn corrupted_record_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = &log_files(temp_dir.path())[0];
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    // last byte of the first value
+    file.seek(SeekFrom::Start(13 + 4 + 5))?;
+    file.write_all(b"X")?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::CorruptedRecord) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corrupted record not detected"),
+    }
+    Ok(())
+}
+
+// A flipped bit in a length field in the middle of the log makes the record run past
+// the end of the file, the records behind it must not be cut off as a torn tail
+#[test]
+fn corrupted_length_in_the_middle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{:03}", i))?;
+    }
+    drop(store);
+
+    let path = &log_files(temp_dir.path())[0];
+    let len = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    // highest byte of the key_len of the third record, every record is 13 + 6 + 8 bytes
+    file.seek(SeekFrom::Start(2 * 27 + 4 + 3))?;
+    file.write_all(&[0x40])?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::CorruptedRecord) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("corrupted length not detected"),
+    }
+    assert_eq!(fs::metadata(path)?.len(), len);
+    Ok(())
+}
+
+// Write two records, cut or damage the tail of the log with `damage`,
+// then check the store reopens with only the first record and keeps working
+fn reopen_after_torn_tail(damage: impl FnOnce(&Path)) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = log_files(temp_dir.path())[0].clone();
+    damage(&path);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // the torn bytes are gone from the file
+    assert_eq!(fs::metadata(&path)?.len(), 13 + 4 + 6);
+
+    // new writes land right behind the last good record
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A crash in the middle of the body of the last record
+#[test]
+fn torn_tail_partial_body() -> Result<()> {
+    reopen_after_torn_tail(|path| {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+    })
+}
+
+// A crash in the middle of the header of the last record
+#[test]
+fn torn_tail_partial_header() -> Result<()> {
+    reopen_after_torn_tail(|path| {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(13 + 4 + 6 + 5).unwrap();
+    })
+}
+
+// The last record has its full length but garbage content
+#[test]
+fn torn_tail_bad_checksum() -> Result<()> {
+    reopen_after_torn_tail(|path| {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::End(-2)).unwrap();
+        file.write_all(&[0, 0]).unwrap();
+    })
+}
+
+// Records are stored as raw bytes behind a fixed size header
+#[test]
+fn binary_record_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // 13 bytes header + key + value, then 13 bytes header + key for the tombstone
+    let path = &log_files(temp_dir.path())[0];
+    assert_eq!(fs::metadata(path)?.len(), (13 + 4 + 6) + (13 + 4));
+    Ok(())
+}
+
+fn set_and_reopen(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    thread::sleep(Duration::from_millis(50));
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn sync_every_write() -> Result<()> {
+    set_and_reopen(KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite))
+}
+
+#[test]
+fn sync_interval() -> Result<()> {
+    set_and_reopen(KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_millis(10))))
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!("every-write".parse::<SyncPolicy>().unwrap(), SyncPolicy::EveryWrite);
+    assert_eq!(
+        "interval:250".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(Duration::from_millis(250))
+    );
+    assert_eq!(SyncPolicy::Interval(Duration::from_millis(250)).to_string(), "interval:250");
+    assert!("interval:0".parse::<SyncPolicy>().is_err());
+    assert!("interval:abc".parse::<SyncPolicy>().is_err());
+    assert!("always".parse::<SyncPolicy>().is_err());
+}
+
+// Small log files should be rolled over and stay readable with few open readers
+#[test]
+fn roll_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(256).reader_cache_size(2);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_files(temp_dir.path()).len() > 5);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in (0..100).rev() {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// No compaction while the stale bytes stay under the threshold
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+
+    // a low threshold and ratio compacts the stale data away
+    drop(store);
+    let options = KvStoreOptions::new().compaction_threshold(64).compaction_ratio(0.1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let size_before: u64 = log_files(temp_dir.path()).iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+    store.set("key".to_owned(), "last".to_owned())?;
+    // compaction runs in the background, dropping the store waits for it
+    drop(store);
+    let size_after: u64 = log_files(temp_dir.path()).iter().map(|p| fs::metadata(p).unwrap().len()).sum();
+    assert!(size_after < size_before);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("last".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for options in [
+        KvStoreOptions::new().compaction_ratio(1.5),
+        KvStoreOptions::new().compaction_ratio(-0.1),
+        KvStoreOptions::new().max_file_size(0),
+        KvStoreOptions::new().reader_cache_size(0),
+    ] {
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KVStoreError::InvalidOption(_)) => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("invalid options accepted"),
+        }
+    }
+}
+
+// Writers and readers keep going while the compactor rewrites the sealed files
+#[test]
+fn concurrent_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4096).compaction_ratio(0.2);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for iter in 0..200 {
+                for key_id in 0..20 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("{}", iter))?;
+                    assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for thread_id in 0..4 {
+        for key_id in 0..20 {
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some("199".to_owned()));
+        }
+    }
+    drop(store);
+    assert!(log_files(temp_dir.path()).len() < 10);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for thread_id in 0..4 {
+        for key_id in 0..20 {
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some("199".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// Readers share one store (no clone) while a writer keeps compacting and rolling files,
+// a small reader cache makes them evict each other's file handles
+#[test]
+fn shared_reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(2048)
+        .compaction_ratio(0.2)
+        .max_file_size(512)
+        .reader_cache_size(1);
+    let store = Arc::new(KvStore::open_with(temp_dir.path(), options)?);
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = Arc::clone(&store);
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..100 {
+                for key_id in 0..20 {
+                    store.set(format!("key{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        let store = Arc::clone(&store);
+        readers.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..100 {
+                for key_id in 0..20 {
+                    let value = store.get(format!("key{}", key_id))?.expect("key vanished");
+                    assert!(value.parse::<u32>().unwrap() < 100);
+                }
+            }
+            Ok(())
+        }));
+    }
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
+// Sealed files read through mmap, while files roll and compaction replaces them
+#[test]
+fn mmap_sealed_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(256)
+        .compaction_threshold(4096)
+        .mmap_sealed_files(true);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..50 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("value{}", iter))?;
+            assert_eq!(store.get(key)?, Some(format!("value{}", iter)));
+        }
+        // all but the last few records sit in sealed files by now
+        for key_id in 0..20 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", iter)));
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value49".to_owned()));
+    }
+    Ok(())
+}
+
+// Repeated gets are served from the value cache, writes invalidate it
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), None);
+    drop(store);
+
+    let options = KvStoreOptions::new().value_cache_size(1024 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.cache_stats(), Some(CacheStats { hits: 1, misses: 1 }));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.cache_stats(), Some(CacheStats { hits: 1, misses: 2 }));
+    Ok(())
+}
+
+// A cache much smaller than the data evicts but never returns a wrong value,
+// also while compaction moves the records
+#[test]
+fn value_cache_eviction_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .value_cache_size(2048)
+        .compaction_threshold(4096);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}_{}", key_id, iter))?;
+        }
+        for _ in 0..2 {
+            for key_id in 0..50 {
+                assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}_{}", key_id, iter)));
+            }
+        }
+    }
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.hits > 0);
+    assert!(stats.misses > 0);
+    Ok(())
+}
+
+// Every command of a batch is applied in order, also after reopening
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key1".to_owned(), "value1".to_owned())
+        .put("key2".to_owned(), "value2".to_owned())
+        .delete("key0".to_owned())
+        .delete("missing".to_owned())
+        .put("key1".to_owned(), "value3".to_owned());
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A batch cut short by a crash is dropped as a whole, `cut` is how many bytes are lost
+fn reopen_after_torn_batch(cut: u64) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "v0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key1".to_owned(), "v1".to_owned())
+        .put("key2".to_owned(), "v2".to_owned())
+        .delete("key0".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let path = log_files(temp_dir.path())[0].clone();
+    let file = OpenOptions::new().write(true).open(&path)?;
+    file.set_len(file.metadata()?.len() - cut)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("v0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    // only the record written before the batch is left
+    assert_eq!(fs::metadata(&path)?.len(), 13 + 4 + 2);
+
+    store.set("key3".to_owned(), "v3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("v3".to_owned()));
+    Ok(())
+}
+
+// The crash hit the middle of the last record of the batch
+#[test]
+fn torn_batch_partial_record() -> Result<()> {
+    reopen_after_torn_batch(3)
+}
+
+// Every record but the last, which commits the batch, reached the disk
+#[test]
+fn torn_batch_missing_commit() -> Result<()> {
+    reopen_after_torn_batch(13 + 4)
+}
+
+// Compaction copies single records out of a batch, they must not wait for the rest of it
+#[test]
+fn write_batch_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..10 {
+        batch.put(format!("key{}", key_id), "value".to_owned());
+    }
+    store.write_batch(batch)?;
+    // overwrite the last key of the batch until a compaction runs
+    for iter in 0..100 {
+        store.set("key9".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    // no hint, so the compacted file is replayed record by record
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..9 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+    assert_eq!(store.get("key9".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// Writers syncing every write share their appends and syncs, each one still gets its own result
+#[test]
+fn group_commit_results() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let barrier = Arc::new(Barrier::new(8));
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        let barrier = Arc::clone(&barrier);
+        handles.push(thread::spawn(move || -> Result<()> {
+            barrier.wait();
+            for i in 0..50 {
+                let key = format!("key{}_{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i))?;
+                if i % 2 == 0 {
+                    store.remove(key.clone())?;
+                    match store.remove(key) {
+                        Err(KVStoreError::KeyNotFound) => {}
+                        other => panic!("second remove returned {:?}", other),
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for thread_id in 0..8 {
+        for i in 0..50 {
+            let expected = if i % 2 == 0 { None } else { Some(format!("value{}", i)) };
+            assert_eq!(store.get(format!("key{}_{}", thread_id, i))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// A transaction sees its own writes and fails if a key it read is written before it commits
+#[test]
+fn transaction_commit_and_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    let sum = store.transaction(|txn| {
+        let a: u64 = txn.get("a".to_owned())?.unwrap().parse().unwrap();
+        let b: u64 = txn.get("b".to_owned())?.unwrap().parse().unwrap();
+        txn.set("sum".to_owned(), (a + b).to_string())?;
+        txn.remove("a".to_owned())?;
+        assert_eq!(txn.get("a".to_owned())?, None);
+        assert_eq!(txn.get("sum".to_owned())?, Some("3".to_owned()));
+        Ok(a + b)
+    })?;
+    assert_eq!(sum, 3);
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("sum".to_owned())?, Some("3".to_owned()));
+
+    // written between the read and the commit
+    let result = store.transaction(|txn| {
+        txn.get("b".to_owned())?;
+        store.set("b".to_owned(), "20".to_owned())?;
+        txn.set("c".to_owned(), "3".to_owned())
+    });
+    assert!(matches!(result, Err(KVStoreError::TransactionConflict)));
+    assert_eq!(store.get("c".to_owned())?, None);
+
+    // written between two reads, the second read already fails
+    let result = store.transaction(|txn| {
+        txn.get("b".to_owned())?;
+        store.set("b".to_owned(), "200".to_owned())?;
+        txn.get("sum".to_owned())
+    });
+    assert!(matches!(result, Err(KVStoreError::TransactionConflict)));
+
+    // a key read as missing conflicts with a set of it
+    let result = store.transaction(|txn| {
+        assert_eq!(txn.get("d".to_owned())?, None);
+        store.set("d".to_owned(), "4".to_owned())?;
+        txn.set("d".to_owned(), "5".to_owned())
+    });
+    assert!(matches!(result, Err(KVStoreError::TransactionConflict)));
+    assert_eq!(store.get("d".to_owned())?, Some("4".to_owned()));
+
+    match store.transaction(|txn| txn.remove("missing".to_owned())) {
+        Err(KVStoreError::KeyNotFound) => {}
+        other => panic!("remove of a missing key returned {:?}", other),
+    }
+    Ok(())
+}
+
+// Concurrent read-modify-write transactions lose no update when retried on conflict
+#[test]
+fn transaction_retry_on_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let barrier = Arc::new(Barrier::new(8));
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        let barrier = Arc::clone(&barrier);
+        handles.push(thread::spawn(move || -> Result<()> {
+            barrier.wait();
+            for _ in 0..25 {
+                retry_on_conflict(1000, || {
+                    store.transaction(|txn| {
+                        let counter: u64 = txn.get("counter".to_owned())?.unwrap().parse().unwrap();
+                        txn.set("counter".to_owned(), (counter + 1).to_string())
+                    })
+                })?;
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+
+    let mut attempts = 0;
+    let result: Result<()> = retry_on_conflict(3, || {
+        attempts += 1;
+        Err(KVStoreError::TransactionConflict)
+    });
+    assert!(matches!(result, Err(KVStoreError::TransactionConflict)));
+    assert_eq!(attempts, 3);
+    Ok(())
+}
+
+// Conditional writes only apply when the key has the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(!store.set_if_equals("key1".to_owned(), "value2".to_owned(), "value3".to_owned())?);
+    assert!(store.set_if_equals("key1".to_owned(), "value1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(!store.remove_if_equals("key1".to_owned(), "value1".to_owned())?);
+    assert!(store.remove_if_equals("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.set_if_equals("key1".to_owned(), "value3".to_owned(), "value4".to_owned())?);
+    assert!(!store.remove_if_equals("missing".to_owned(), "value".to_owned())?);
+
+    // concurrent increments, each retried until its swap wins
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let counter = store.get("counter".to_owned())?;
+                        let next = counter.as_ref().map_or(0, |c| c.parse::<u64>().unwrap()) + 1;
+                        if store.compare_and_swap("counter".to_owned(), counter, Some(next.to_string()))? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Increments from many threads all land, a value that is no integer is left alone
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.incr("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("398".to_owned()));
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    match store.incr("name".to_owned(), 1) {
+        Err(KVStoreError::NotAnInteger(key)) => assert_eq!(key, "name"),
+        other => panic!("incr of a string returned {:?}", other),
+    }
+    assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(store.incr("max".to_owned(), 1), Err(KVStoreError::IntegerOverflow(_))));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("398".to_owned()));
+    Ok(())
+}
+
+// A key set with a ttl is gone once it passes, also after reopening
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(60))?;
+    store.set_with_ttl("replaced".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+    store.set("replaced".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("replaced".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.scan_prefix(String::new())?.len(), 2);
+    assert!(matches!(store.remove("short".to_owned()), Err(KVStoreError::KeyNotFound)));
+    assert!(store.set_if_absent("short".to_owned(), "again".to_owned())?);
+    store.set_with_ttl("reopened".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("reopened".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    thread::sleep(Duration::from_millis(300));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("reopened".to_owned())?, None);
+    Ok(())
+}
+
+// The sweeper leaves keys alone whose ttl was removed or extended before it passed
+#[test]
+fn ttl_sweep_after_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().expiry_sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_with_ttl("plain".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+    store.set("plain".to_owned(), "new".to_owned())?;
+    store.set_with_ttl("extended".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("extended".to_owned(), "new".to_owned(), Duration::from_secs(60))?;
+    store.set_with_ttl("removed".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+    store.remove("removed".to_owned())?;
+    store.set("removed".to_owned(), "new".to_owned())?;
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+    thread::sleep(Duration::from_millis(400));
+
+    assert_eq!(store.get("short".to_owned())?, None);
+    for key in ["plain", "extended", "removed"] {
+        assert_eq!(store.get(key.to_owned())?, Some("new".to_owned()));
+    }
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key in ["plain", "extended", "removed"] {
+        assert_eq!(store.get(key.to_owned())?, Some("new".to_owned()));
+    }
+    Ok(())
+}
+
+// The sweeper turns expired keys into stale bytes, which compaction then drops
+#[test]
+fn ttl_sweep_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // the first sweep comes after every key has expired, so it compacts them all at once
+    let options = KvStoreOptions::new()
+        .expiry_sweep_interval(Duration::from_millis(400))
+        .compaction_threshold(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    // more keys than one sweep removes per hold of the writer lock
+    for key_id in 0..2500 {
+        store.set_with_ttl(format!("key{}", key_id), "x".repeat(100), Duration::from_millis(200))?;
+    }
+    store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_millis(1500))?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(700));
+    // dropping the store waits for the compaction started by the sweeper
+    drop(store);
+
+    let data_len: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("txt".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(data_len < 1000, "expired records left in {} bytes of logs", data_len);
+    let hints = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("hint".as_ref()))
+        .count();
+    assert_eq!(hints, 1);
+
+    // the ttl of long comes back from the hint
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(900));
+    assert_eq!(store.get("long".to_owned())?, None);
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Keys and values are raw bytes, also across compaction and reopen
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(64).compaction_ratio(0.1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let keys: Vec<Vec<u8>> = vec![vec![0xff, 0x00, 0x01], vec![0xff, 0x00, 0x02], vec![0x00], vec![0xfe]];
+    for (i, key) in keys.iter().enumerate() {
+        store.set_bytes(key, &[0x80, i as u8, 0x00])?;
+    }
+    store.remove_bytes(&[0xfe])?;
+    assert_eq!(store.get_bytes(&[0xff, 0x00, 0x01])?, Some(vec![0x80, 0, 0x00]));
+    assert_eq!(store.get_bytes(&[0xfe])?, None);
+    assert_eq!(
+        store.scan_bytes(&[0x00], Some(&[0xff, 0x00, 0x02]), 10)?,
+        vec![(vec![0x00], vec![0x80, 2, 0x00]), (vec![0xff, 0x00, 0x01], vec![0x80, 0, 0x00])]
+    );
+    assert_eq!(store.scan_prefix_bytes(&[0xff, 0x00])?.len(), 2);
+    // the String methods can not return these
+    match store.get_bytes(&[0x00]).and_then(|_| store.scan_prefix("".to_owned())) {
+        Err(KVStoreError::Utf8Error(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // overwrite enough to compact, the hint files written by it hold binary keys too
+    for iter in 0..100u8 {
+        store.set_bytes(&[0xff, 0x00, 0x02], &[iter, 0xff])?;
+    }
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_bytes(&[0xff, 0x00, 0x02])?, Some(vec![99, 0xff]));
+    assert_eq!(store.get_bytes(&[0x00])?, Some(vec![0x80, 2, 0x00]));
+    assert_eq!(store.get_bytes(&[0xfe])?, None);
+    Ok(())
+}
+
+// ttl, compare and swap, incr and transactions take binary keys and values as well
+#[test]
+fn binary_atomic_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl_bytes(&[0xff, 0x01], &[0x80], Duration::from_millis(200))?;
+    assert!(store.compare_and_swap_bytes(&[0xff, 0x02], None, Some(&[0xfe]))?);
+    assert!(!store.compare_and_swap_bytes(&[0xff, 0x02], Some(&[0x80]), None)?);
+    assert_eq!(store.incr_bytes(&[0xff, 0x03], 2)?, 2);
+    store.transaction(|txn| {
+        let value = txn.get_bytes(&[0xff, 0x02])?.unwrap_or_default();
+        txn.set_bytes(&[0xff, 0x04], &value)?;
+        txn.remove_bytes(&[0xff, 0x02])
+    })?;
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, Some(vec![0x80]));
+    assert_eq!(store.get_bytes(&[0xff, 0x02])?, None);
+    assert_eq!(store.get_bytes(&[0xff, 0x03])?, Some(b"2".to_vec()));
+    assert_eq!(store.get_bytes(&[0xff, 0x04])?, Some(vec![0xfe]));
+    // a value that is no UTF-8 is no integer either
+    assert!(matches!(store.incr_bytes(&[0xff, 0x04], 1), Err(KVStoreError::NotAnInteger(_))));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, None);
+    Ok(())
+}
+
+// Only one store at a time may have a directory open, the lock goes with the last clone
+#[test]
+fn store_dir_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_millis(1)));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::StoreLocked(_)) => {}
+        other => panic!("second open returned {:?}", other.map(|_| ())),
+    }
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    // the background threads are joined, nothing holds the directory right after the drop
+    drop(clone);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A snapshot keeps returning the pairs as they were when it was taken
+#[test]
+fn snapshot_stable_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    let seq = snapshot.seq();
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key5".to_owned(), "new".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.put("key3", "batched").delete("key4");
+    store.write_batch(batch)?;
+
+    assert_eq!(snapshot.seq(), seq);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key5".to_owned())?, None);
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot.iter().collect::<Result<_>>()?;
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..5)
+        .map(|i| (format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()))
+        .collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(snapshot.range_bytes(b"key1", Some(b"key3")).count(), 2);
+    assert_eq!(snapshot.range_bytes(b"key3", Some(b"key1")).count(), 0);
+
+    // a later snapshot sees the later writes
+    let later = store.snapshot()?;
+    assert!(later.seq() > seq);
+    assert_eq!(later.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(later.iter().count(), 4);
+    Ok(())
+}
+
+// Compaction leaves the files a snapshot reads from on disk until it is dropped
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(64).compaction_ratio(0.1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 0..100 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("{}", iter))?;
+        }
+    }
+    // waits for the running compaction
+    drop(store);
+    let files_with_snapshot = log_files(temp_dir.path()).len();
+
+    for i in 0..10 {
+        assert_eq!(snapshot.get(format!("key{}", i))?, Some("old".to_owned()));
+    }
+    assert_eq!(snapshot.iter().count(), 10);
+    drop(snapshot);
+    assert!(log_files(temp_dir.path()).len() < files_with_snapshot);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// Files kept for a snapshot when the store went away are removed by the next open
+#[test]
+fn snapshot_files_removed_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(64).compaction_ratio(0.1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    let snapshot = store.snapshot()?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    // as if the process had stopped with the snapshot still alive
+    std::mem::forget(snapshot);
+    let files_before = log_files(temp_dir.path()).len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(log_files(temp_dir.path()).len() < files_before);
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// A backup taken while other threads write holds one consistent state of the store:
+// each batch sets a key and the counter naming it, so a key may only be there with its counter
+#[test]
+fn backup_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // small files and an eager compaction, so both run during the backup
+    let options = KvStoreOptions::new().max_file_size(4096).compaction_threshold(1024).compaction_ratio(0.1);
+    let store = KvStore::open_with(temp_dir.path().join("store"), options)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || -> Result<()> {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let mut batch = WriteBatch::new();
+                    batch.put(format!("{}-{}", t, i), "value").put(format!("counter{}", t), i.to_string());
+                    store.write_batch(batch)?;
+                    store.set(format!("overwritten{}", t), i.to_string())?;
+                    i += 1;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(100));
+    store.backup_to(temp_dir.path().join("backup"))?;
+    thread::sleep(Duration::from_millis(100));
+    stop.store(true, Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    let restored = KvStore::restore(temp_dir.path().join("backup"), temp_dir.path().join("restored"))?;
+    for t in 0..4 {
+        let counter = restored.get(format!("counter{}", t))?.map(|value| value.parse::<usize>().unwrap());
+        let keys = restored.scan_prefix(format!("{}-", t))?.len();
+        assert_eq!(keys, counter.map_or(0, |counter| counter + 1));
+    }
+    // the restored store is a store like any other, the original goes on without it
+    restored.set("after".to_owned(), "restore".to_owned())?;
+    assert_eq!(store.get("after".to_owned())?, None);
+    Ok(())
+}
+
+// Backups and restores never write into a directory holding anything
+#[test]
+fn backup_restore_targets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("temp".to_owned(), "value2".to_owned(), Duration::from_secs(3600))?;
+
+    fs::create_dir(temp_dir.path().join("full"))?;
+    fs::write(temp_dir.path().join("full").join("file"), "data")?;
+    match store.backup_to(temp_dir.path().join("full")) {
+        Err(KVStoreError::DirectoryNotEmpty(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match KvStore::restore(temp_dir.path().join("full"), temp_dir.path().join("restored")) {
+        Err(KVStoreError::InvalidBackup(_)) => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    store.backup_to(temp_dir.path().join("backup"))?;
+    match KvStore::restore(temp_dir.path().join("backup"), temp_dir.path().join("full")) {
+        Err(KVStoreError::DirectoryNotEmpty(_)) => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    let restored = KvStore::restore(temp_dir.path().join("backup"), temp_dir.path().join("restored"))?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("temp".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
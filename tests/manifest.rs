@@ -0,0 +1,165 @@
+// The MANIFEST decides which log files are live, whatever else sits in the directory
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+fn manifest(dir: &TempDir) -> serde_json::Value {
+    serde_json::from_slice(&fs::read(dir.path().join("MANIFEST")).unwrap()).unwrap()
+}
+
+// A new store lists its first log file
+#[test]
+fn new_store_writes_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest = manifest(&temp_dir);
+    assert_eq!(manifest["format_version"], 2);
+    assert_eq!(manifest["generation"], 0);
+    assert_eq!(manifest["files"], serde_json::json!([0]));
+    Ok(())
+}
+
+// Rolling to a new file and compacting both update the list
+#[test]
+fn manifest_follows_rolls_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(1).compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(manifest(&temp_dir)["files"], serde_json::json!([0, 1, 2, 3]));
+
+    // every overwrite makes the store stale enough to compact
+    let options = KvStoreOptions::new().max_file_size(1).compaction_threshold(0).compaction_ratio(0.0);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    // data_0..data_3 are compacted into data_4, new writes go to data_5
+    let manifest = manifest(&temp_dir);
+    assert_eq!(manifest["files"], serde_json::json!([4, 5]));
+    assert_eq!(manifest["generation"], 1);
+    assert_eq!(manifest["pending_compaction"], serde_json::Value::Null);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A log file the manifest does not list is never replayed
+#[test]
+fn stray_log_file_ignored() -> Result<()> {
+    let stray = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(stray.path())?;
+    store.set("key1".to_owned(), "stray".to_owned())?;
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::copy(stray.path().join("data_0.txt"), temp_dir.path().join("data_7.txt"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A directory written before the manifest listed the files takes them from the disk
+#[test]
+fn open_without_file_list() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    fs::write(temp_dir.path().join("MANIFEST"), "{\"pending_compaction\":null}")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(manifest(&temp_dir)["files"], serde_json::json!([0, 1, 2]));
+
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A store written by a newer format is refused rather than misread
+#[test]
+fn newer_format_version_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let mut manifest = manifest(&temp_dir);
+    manifest["format_version"] = 99.into();
+    fs::write(temp_dir.path().join("MANIFEST"), manifest.to_string())?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// A store of the previous format is upgraded, its old hints are dropped
+#[test]
+fn older_format_version_upgraded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut old = manifest(&temp_dir);
+    old["format_version"] = 1.into();
+    fs::write(temp_dir.path().join("MANIFEST"), old.to_string())?;
+    fs::write(temp_dir.path().join("data_0.hint"), b"old hint")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(manifest(&temp_dir)["format_version"], 2);
+    assert!(!temp_dir.path().join("data_0.hint").exists());
+    Ok(())
+}
+
+// A log of the first, JSON encoded format is converted to binary records on open
+#[test]
+fn legacy_json_log_converted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut legacy = String::new();
+    for i in 0..50 {
+        legacy.push_str(&format!("{{\"SET\":[\"key{}\",\"value{}\"]}}", i, i));
+    }
+    legacy.push_str("{\"RM\":\"key0\"}{\"SET\":[\"key1\",\"new\"]}");
+    fs::write(temp_dir.path().join("data_0.txt"), &legacy)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    store.set("key50".to_owned(), "value50".to_owned())?;
+    drop(store);
+    assert_eq!(manifest(&temp_dir)["files"], serde_json::json!([0]));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key49".to_owned())?, Some("value49".to_owned()));
+    assert_eq!(store.get("key50".to_owned())?, Some("value50".to_owned()));
+    Ok(())
+}
+
+// A legacy log that does not parse is refused and left as it is, never truncated
+#[test]
+fn broken_legacy_json_log_refused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = "{\"SET\":[\"key1\",\"value1\"]}{\"SET\":[\"key2\",\"val";
+    fs::write(temp_dir.path().join("data_0.txt"), legacy)?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert_eq!(fs::read_to_string(temp_dir.path().join("data_0.txt"))?, legacy);
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+    Ok(())
+}
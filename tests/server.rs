@@ -0,0 +1,147 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::protocol::{read_message, write_message};
+use kvs::{KvServer, KvStore, KvsClient, KvsEngine, Request, Response, Result};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn send(addr: &str, request: &Request) -> Response {
+    let mut stream = TcpStream::connect(addr).expect("unable to connect to server");
+    write_message(&mut stream, request).unwrap();
+    stream.flush().unwrap();
+    read_message(stream).unwrap()
+}
+
+// Setting the stop flag should make `serve` return, and the data written
+// before the shutdown should be on disk.
+#[test]
+fn serve_returns_after_stop_flag() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4010";
+    let is_stop = Arc::new(AtomicBool::new(false));
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::clone(&is_stop),
+    );
+    let handle = thread::spawn(move || server.serve(&addr.to_owned()));
+    thread::sleep(Duration::from_millis(500));
+
+    match send(addr, &Request::SET(b"key1".to_vec(), b"value1".to_vec())) {
+        Response::Ok(None) => {}
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    is_stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")?;
+
+    // No longer listening
+    assert!(TcpStream::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The handle returned by `shutdown_handle` stops the server as well.
+#[test]
+fn shutdown_handle_stops_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let is_stop = server.shutdown_handle();
+    let handle = thread::spawn(move || server.serve(&"127.0.0.1:4011".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+
+    is_stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")?;
+    Ok(())
+}
+
+// Several requests should be answered in order over a single connection.
+#[test]
+fn multiple_requests_on_one_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4012";
+    let is_stop = Arc::new(AtomicBool::new(false));
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::clone(&is_stop),
+    );
+    let handle = thread::spawn(move || server.serve(&addr.to_owned()));
+    thread::sleep(Duration::from_millis(500));
+
+    let stream = TcpStream::connect(addr).expect("unable to connect to server");
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = stream;
+    let mut request = |request: Request| -> Response {
+        write_message(&mut writer, &request).unwrap();
+        writer.flush().unwrap();
+        read_message(&mut reader).unwrap()
+    };
+
+    for i in 0..10 {
+        match request(Request::SET(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())) {
+            Response::Ok(None) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    for i in 0..10 {
+        match request(Request::GET(format!("key{}", i).into_bytes())) {
+            Response::Ok(Some(value)) => assert_eq!(value, format!("value{}", i).into_bytes()),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    match request(Request::RM(b"missing".to_vec())) {
+        Response::Err(err) => assert!(err.contains("Key not found")),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // The connection is still open and idle, it must not keep the server alive
+    is_stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")?;
+    Ok(())
+}
+
+// Idle persistent connections must not hold on to the pool threads,
+// even when there are more of them than threads.
+#[test]
+fn idle_connections_do_not_starve_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4013";
+    let is_stop = Arc::new(AtomicBool::new(false));
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::clone(&is_stop),
+    );
+    let handle = thread::spawn(move || server.serve(&addr.to_owned()));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut idle_clients = Vec::new();
+    for i in 0..4 {
+        let mut client = KvsClient::connect_timeout(addr, Duration::from_secs(2))?;
+        client.set(format!("key{}", i), format!("value{}", i))?;
+        idle_clients.push(client);
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    // A starved request would fail with the read timeout
+    let mut client = KvsClient::connect_timeout(addr, Duration::from_secs(2))?;
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+    for (i, client) in idle_clients.iter_mut().enumerate() {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    is_stop.store(true, Ordering::SeqCst);
+    handle.join().expect("server thread panicked")?;
+    Ok(())
+}
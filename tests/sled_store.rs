@@ -0,0 +1,402 @@
+use kvs::{KVStoreError, KvStoreOptions, KvsEngine, Result, SledKvStore, Snapshot, SyncPolicy, WriteBatch};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Should return pairs in key order within [start, end), up to limit
+#[test]
+fn sled_scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    for i in (0..10).rev() {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let pairs = store.scan("key3".to_owned(), Some("key7".to_owned()), 100)?;
+    let expected: Vec<(String, String)> = [3, 4, 6]
+        .iter()
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    assert_eq!(store.scan("key8".to_owned(), None, 100)?.len(), 2);
+    assert_eq!(store.scan(String::new(), None, 3)?.len(), 3);
+    assert!(store.scan("key7".to_owned(), Some("key3".to_owned()), 100)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sled_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("user:".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert!(store.scan_prefix("none".to_owned())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sled_sync_policies() -> Result<()> {
+    for sync_policy in [
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(sync_policy);
+        let store = SledKvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.flush()?;
+
+        drop(store);
+        let store = SledKvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// A db another store holds fails to open with StoreLocked, it opens again right after that store is dropped
+#[test]
+fn sled_store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match SledKvStore::open(temp_dir.path()) {
+        Err(KVStoreError::StoreLocked(_)) => {}
+        other => panic!("second open returned {:?}", other.map(|_| ())),
+    }
+    for _ in 0..20 {
+        drop(store);
+        store = SledKvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key1".to_owned(), "value1".to_owned())
+        .delete("key0".to_owned())
+        .delete("missing".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// sled reruns conflicting transactions itself, an error of the closure aborts all its writes
+#[test]
+fn sled_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open_with(temp_dir.path(), KvStoreOptions::new().sync_policy(SyncPolicy::Never))?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    store.transaction(|txn| {
+                        let counter: u64 = txn.get("counter".to_owned())?.unwrap().parse().unwrap();
+                        txn.set("counter".to_owned(), (counter + 1).to_string())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+
+    let result = store.transaction(|txn| {
+        txn.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(txn.get("key1".to_owned())?, Some("value1".to_owned()));
+        txn.remove("missing".to_owned())
+    });
+    assert!(matches!(result, Err(KVStoreError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert!(!store.set_if_equals("key1".to_owned(), "value2".to_owned(), "value3".to_owned())?);
+    assert!(store.set_if_equals("key1".to_owned(), "value1".to_owned(), "value3".to_owned())?);
+    assert!(!store.remove_if_equals("key1".to_owned(), "value1".to_owned())?);
+    assert!(store.remove_if_equals("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(matches!(store.incr("name".to_owned(), 1), Err(KVStoreError::NotAnInteger(_))));
+    assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().expiry_sweep_interval(Duration::from_millis(50));
+    let store = SledKvStore::open_with(temp_dir.path(), options.clone())?;
+
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(60))?;
+    store.set_with_ttl("replaced".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+    store.set("replaced".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("replaced".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.scan_prefix(String::new())?.len(), 2);
+    assert!(matches!(store.remove("short".to_owned()), Err(KVStoreError::KeyNotFound)));
+    drop(store);
+
+    let store = SledKvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Values written by other sled users need not be UTF-8
+#[test]
+fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    store.set_bytes(b"key1", &[0xff, 0xfe])?;
+    store.set_bytes(&[0x80], b"value2")?;
+    assert_eq!(store.get_bytes(b"key1")?, Some(vec![0xff, 0xfe]));
+    assert_eq!(store.scan_bytes(&[0x80], None, 10)?, vec![(vec![0x80], b"value2".to_vec())]);
+    match store.get("key1".to_owned()) {
+        Err(KVStoreError::Utf8Error(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    store.remove_bytes(&[0x80])?;
+    assert_eq!(store.get_bytes(&[0x80])?, None);
+    Ok(())
+}
+
+// ttl, compare and swap, incr and transactions take binary keys and values as well
+#[test]
+fn sled_binary_atomic_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    store.set_with_ttl_bytes(&[0xff, 0x01], &[0x80], Duration::from_millis(200))?;
+    assert!(store.compare_and_swap_bytes(&[0xff, 0x02], None, Some(&[0xfe]))?);
+    assert!(!store.compare_and_swap_bytes(&[0xff, 0x02], Some(&[0x80]), None)?);
+    assert_eq!(store.incr_bytes(&[0xff, 0x03], 2)?, 2);
+    store.transaction(|txn| {
+        let value = txn.get_bytes(&[0xff, 0x02])?.unwrap_or_default();
+        txn.set_bytes(&[0xff, 0x04], &value)?;
+        txn.remove_bytes(&[0xff, 0x02])
+    })?;
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, Some(vec![0x80]));
+    assert_eq!(store.get_bytes(&[0xff, 0x02])?, None);
+    assert_eq!(store.get_bytes(&[0xff, 0x03])?, Some(b"2".to_vec()));
+    assert_eq!(store.get_bytes(&[0xff, 0x04])?, Some(vec![0xfe]));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, None);
+    Ok(())
+}
+
+// A snapshot keeps returning the pairs as they were when it was taken
+#[test]
+fn sled_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_with_ttl("temp".to_owned(), "gone".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    let snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key5".to_owned(), "new".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key5".to_owned())?, None);
+    assert_eq!(snapshot.get("temp".to_owned())?, None);
+    let keys: Vec<Vec<u8>> = snapshot.iter().map(|pair| pair.map(|(key, _)| key)).collect::<Result<_>>()?;
+    assert_eq!(keys, (0..5).map(|i| format!("key{}", i).into_bytes()).collect::<Vec<_>>());
+    assert_eq!(snapshot.range_bytes(b"key1", Some(b"key3")).count(), 2);
+    Ok(())
+}
+
+// A snapshot copies nothing up front, so writes of every kind after it must keep its view,
+// the sweeper removing a key that expired after the snapshot as well
+#[test]
+fn sled_snapshot_after_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().expiry_sweep_interval(Duration::from_millis(10));
+    let store = SledKvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..6 {
+        store.set(format!("key{}", i), i.to_string())?;
+    }
+    store.set_with_ttl("temp".to_owned(), "value".to_owned(), Duration::from_millis(100))?;
+    let snapshot = store.snapshot()?;
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"key0".to_vec(), b"batch".to_vec());
+    batch.delete(b"key1".to_vec());
+    batch.put(b"key1".to_vec(), b"again".to_vec());
+    store.write_batch(batch)?;
+    assert!(store.compare_and_swap("key2".to_owned(), Some("2".to_owned()), None)?);
+    store.incr("key3".to_owned(), 10)?;
+    store.transaction(|txn| {
+        txn.remove("key4".to_owned())?;
+        txn.set("key6".to_owned(), "new".to_owned())
+    })?;
+    store.set_with_ttl("key5".to_owned(), "ttl".to_owned(), Duration::from_secs(60))?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("temp".to_owned())?, None);
+
+    let mut expected: Vec<(String, String)> = (0..6).map(|i| (format!("key{}", i), i.to_string())).collect();
+    expected.push(("temp".to_owned(), "value".to_owned()));
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot.iter().collect::<Result<_>>()?;
+    let pairs: Vec<(String, String)> = pairs.into_iter()
+        .map(|(key, value)| (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap()))
+        .collect();
+    assert_eq!(pairs, expected);
+    for (key, value) in expected {
+        assert_eq!(snapshot.get(key)?, Some(value));
+    }
+    assert_eq!(snapshot.get("key6".to_owned())?, None);
+    assert_eq!(snapshot.range_bytes(b"key2", Some(b"key5")).count(), 3);
+    Ok(())
+}
+
+// Transfers between keys keep their sum, so every snapshot taken meanwhile must sum up to it
+#[test]
+fn sled_snapshot_during_transfers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open_with(temp_dir.path(), KvStoreOptions::new().sync_policy(SyncPolicy::Never))?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), "100".to_owned())?;
+    }
+    let is_stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let store = store.clone();
+        let is_stop = Arc::clone(&is_stop);
+        thread::spawn(move || -> Result<()> {
+            let mut i = 0;
+            while !is_stop.load(Ordering::SeqCst) {
+                let (from, to) = (format!("key{:02}", i % 20), format!("key{:02}", (i * 7 + 3) % 20));
+                store.transaction(|txn| {
+                    let amount: i64 = txn.get(from.clone())?.unwrap().parse().unwrap();
+                    let other: i64 = txn.get(to.clone())?.unwrap().parse().unwrap();
+                    //move half of it, or all of it with a remove and a set again
+                    txn.remove(from.clone())?;
+                    if from != to {
+                        txn.set(to.clone(), (other + amount / 2).to_string())?;
+                    }
+                    txn.set(from.clone(), (amount - amount / 2).to_string())
+                })?;
+                i += 1;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..50 {
+        let snapshot = store.snapshot()?;
+        thread::sleep(Duration::from_millis(1));
+        let values: Vec<i64> = snapshot.iter()
+            .map(|pair| pair.map(|(_, value)| String::from_utf8(value).unwrap().parse().unwrap()))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 20);
+        assert_eq!(values.iter().sum::<i64>(), 2000);
+    }
+    is_stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap()
+}
+
+// A backup taken while other threads write holds one consistent state of the store
+#[test]
+fn sled_backup_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvStore::open_with(temp_dir.path().join("store"), KvStoreOptions::new())?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || -> Result<()> {
+                let mut i = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let mut batch = WriteBatch::new();
+                    batch.put(format!("{}-{}", t, i), "value").put(format!("counter{}", t), i.to_string());
+                    store.write_batch(batch)?;
+                    i += 1;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    store.set_with_ttl("temp".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+    let short_ttl = Duration::from_secs(5);
+    let short_set_at = Instant::now();
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), short_ttl)?;
+    thread::sleep(Duration::from_millis(100));
+    store.backup_to(temp_dir.path().join("backup"))?;
+    stop.store(true, Ordering::SeqCst);
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+
+    match SledKvStore::restore(temp_dir.path().join("store"), temp_dir.path().join("backup")) {
+        Err(KVStoreError::DirectoryNotEmpty(_)) => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    let restored = SledKvStore::restore(temp_dir.path().join("backup"), temp_dir.path().join("restored"))?;
+    for t in 0..4 {
+        let counter = restored.get(format!("counter{}", t))?.map(|value| value.parse::<usize>().unwrap());
+        let keys = restored.scan_prefix(format!("{}-", t))?.len();
+        assert_eq!(keys, counter.map_or(0, |counter| counter + 1));
+    }
+    assert_eq!(restored.get("temp".to_owned())?, Some("value".to_owned()));
+    // the expiries are copied with the values, the backup and restore may take a while on a slow machine
+    if short_set_at.elapsed() < short_ttl {
+        assert_eq!(restored.get("short".to_owned())?, Some("value".to_owned()));
+    }
+    thread::sleep(short_ttl.saturating_sub(short_set_at.elapsed()) + Duration::from_millis(100));
+    assert_eq!(restored.get("short".to_owned())?, None);
+    Ok(())
+}